@@ -0,0 +1,75 @@
+// Times the interpreter on recursive programs. Run with 'cargo bench'.
+//
+// Each program is run a few times through the release binary, on both the
+// tree walker and the bytecode VM, and the fastest run is reported, which
+// filters out most noise from the rest of the system.
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))\n\
+         (write (fib 22))",
+    ),
+    (
+        "tail loop",
+        "(defun loop (n acc) (if (< n 1) acc (loop (- n 1) (+ acc n))))\n\
+         (write (loop 300000 0))",
+    ),
+    (
+        "deep recursion",
+        "(defun count (n) (if (< n 1) 0 (+ 1 (count (- n 1)))))\n\
+         (write (count 300000))",
+    ),
+    (
+        "nested lets",
+        "(defun step (n)\n\
+           (let a (+ n 1) (let b (+ a 1) (let c (+ b 1) (let d (+ c 1) (- d 5))))))\n\
+         (defun loop (n) (if (< n 1) n (loop (step n))))\n\
+         (write (loop 100000))",
+    ),
+    (
+        "closures",
+        "(defun make-adder (n) (lambda (x) (+ x n)))\n\
+         (defun loop (n f) (if (< n 1) (f 0) (loop (- n 1) (make-adder n))))\n\
+         (write (loop 200000 (make-adder 0)))",
+    ),
+];
+
+fn run_once(args: &[&str], source: &str) -> Duration {
+    let start = Instant::now();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:<16} {:>11} {:>11}", "", "tree walker", "vm");
+    for (name, source) in PROGRAMS {
+        let fastest = |args: &[&str]| {
+            let time = (0..RUNS).map(|_| run_once(args, source)).min().unwrap();
+            time.as_secs_f64() * 1000.0
+        };
+        println!(
+            "{:<16} {:>8.1} ms {:>8.1} ms",
+            name,
+            fastest(&[]),
+            fastest(&["--vm"])
+        );
+    }
+}
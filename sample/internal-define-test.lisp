@@ -0,0 +1,30 @@
+(defun writeln (x) (write x) (write "\n"))
+
+; Definitions in a body are local to each call, and can refer to each other
+; before they're defined.
+(defun parity (n)
+  (defun even? (k) (if (< k 1) true (odd? (- k 1))))
+  (define odd? (lambda (k) (if (< k 1) false (even? (- k 1)))))
+  (list (even? n) (odd? n)))
+(writeln (parity 7))
+(writeln (try (eval 'even?) (catch e (error-kind e))))
+
+; Closures keep the definitions of the call which made them.
+(defun make-account (balance)
+  (define deposits 0)
+  (defun deposit (amount)
+    (set! balance (+ balance amount))
+    (set! deposits (+ deposits 1))
+    (list deposits balance))
+  deposit)
+(define a (make-account 10))
+(define b (make-account 100))
+(a 5)
+(writeln (list (a 1) (b 1)))
+
+; A local definition shadows a global of the same name.
+(define scale 10)
+(defun scaled (x)
+  (define scale 2)
+  (+ x scale))
+(writeln (list (scaled 3) scale))
@@ -0,0 +1,17 @@
+(defun writeln (x) (write x) (write "\n"))
+
+(defmacro unless (test then else) (list 'if test else then))
+(writeln (unless (< 1 2) "no" "yes"))
+
+(defmacro twice (form) `(let v ,form (+ v v)))
+(writeln (twice (- 10 1)))
+
+(defmacro my-or (a b) `(let tmp ,a (if tmp tmp ,b)))
+(writeln (my-or false (< 1 2)))
+(writeln (my-or (unless true false true) false))
+
+(writeln (macroexpand-1 '(my-or x y)))
+(writeln (macroexpand '(unless a b c)))
+(writeln (eval '(unless false "from eval" "wrong")))
+
+(writeln (let unless (lambda (x y z) "shadowed") (unless 1 2 3)))
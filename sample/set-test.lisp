@@ -0,0 +1,21 @@
+(defun writeln (x) (write x) (write "\n"))
+
+(define greeting "hello")
+(writeln greeting)
+(set! greeting (str (list greeting "again")))
+(writeln greeting)
+
+; Each counter has its own count, which only its closure can change.
+(defun make-counter ()
+  (let count 0
+    (lambda () (set! count (+ count 1)) count)))
+(define a (make-counter))
+(define b (make-counter))
+(a)
+(a)
+(writeln (list (a) (b)))
+
+(define total 0)
+(defun add (n) (set! total (+ total n)) total)
+(add 5)
+(writeln (add 10))
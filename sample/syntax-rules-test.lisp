@@ -0,0 +1,31 @@
+(defun writeln (x) (write x) (write "\n"))
+
+(define-syntax my-or
+  (syntax-rules ()
+    ((_) false)
+    ((_ e) e)
+    ((_ e rest ...) (let tmp e (if tmp tmp (my-or rest ...))))))
+(writeln (my-or))
+(writeln (my-or false false true))
+; The tmp bound by my-or doesn't capture the user's.
+(writeln (let tmp true (my-or false tmp)))
+
+(define-syntax pairs
+  (syntax-rules ()
+    ((_ (a b) ...) (list (list b a) ...))))
+(writeln (pairs (1 2) (3 4) (5 6)))
+
+(define-syntax choose
+  (syntax-rules (then else)
+    ((_ c then t else e) (if c t e))))
+(writeln (choose (< 1 2) then "then" else "else"))
+
+; The helper in the template is the global, whatever is bound locally.
+(defun helper (x) (+ x 1))
+(define-syntax inc (syntax-rules () ((_ e) (helper e))))
+(writeln (let helper 100 (inc helper)))
+
+(define-syntax safely
+  (syntax-rules ()
+    ((_ e) (try e (catch err (error-message err))))))
+(writeln (let err "mine" (safely (error err))))
@@ -0,0 +1,23 @@
+(defun writeln (x) (write x) (write "\n"))
+
+(defun safe-add (x y)
+  (try (+ x y)
+       (catch e
+         (writeln (error-kind e))
+         (writeln (error-message e))
+         0)))
+(writeln (safe-add 1 2))
+(writeln (safe-add 1 "two"))
+
+(writeln (try (throw (list 1 2)) (catch e (error-data e))))
+(writeln (try (raise "raised") (catch e (error-message e))))
+
+(try (writeln "body") (finally (writeln "finally after success")))
+(writeln
+  (try
+    (try (error "inner") (finally (writeln "finally before the catch")))
+    (catch e (error-message e))))
+
+(writeln (try (try (throw 1) (catch e (throw e))) (catch e (error-kind e))))
+
+(unwind-protect (writeln "protected") (writeln "cleanup"))
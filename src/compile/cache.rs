@@ -0,0 +1,417 @@
+use super::{Chunk, Function, Op};
+use crate::eval::value::Value;
+use crate::span::{Position, Span};
+use std::rc::Rc;
+
+// Compiled programs can be saved so later runs of the same source skip
+// parsing and compiling. A cache file is laid out as:
+//
+//   magic          4 bytes, "RSPC"
+//   version        u32, FORMAT_VERSION
+//   source hash    u64, hash of the source the program was compiled from
+//   source length  u64
+//   optimized      u8, 1 if the program was optimized before compiling
+//   payload length u64
+//   checksum       u64, hash of the payload
+//   payload        the encoded Function
+//
+// All numbers are little endian. A file with the wrong magic, version or
+// checksum, or compiled from different source or options, is never used.
+const MAGIC: &[u8; 4] = b"RSPC";
+
+// Must be changed whenever the instructions, the encoding or the meaning of
+// compiled code changes, so old cache files are ignored.
+const FORMAT_VERSION: u32 = 7;
+
+// Encodes function, compiled from source, as the contents of a cache file.
+pub fn encode(function: &Function, source: &str, optimized: bool) -> Result<Vec<u8>, String> {
+    let mut payload = Writer::default();
+    payload.function(function)?;
+    let payload = payload.bytes;
+
+    let mut file = Writer::default();
+    file.bytes.extend_from_slice(MAGIC);
+    file.u32(FORMAT_VERSION);
+    file.u64(hash(source.as_bytes()));
+    file.u64(source.len() as u64);
+    file.u8(optimized as u8);
+    file.u64(payload.len() as u64);
+    file.u64(hash(&payload));
+    file.bytes.extend_from_slice(&payload);
+    Ok(file.bytes)
+}
+
+// Decodes the contents of a cache file, as long as it was written by this
+// version of the interpreter and compiled from source the same way.
+pub fn decode(bytes: &[u8], source: &str, optimized: bool) -> Result<Rc<Function>, String> {
+    let mut file = Reader { bytes, position: 0 };
+    if file.take(MAGIC.len())? != MAGIC {
+        return Err("Not a cache file.".to_owned());
+    }
+    if file.u32()? != FORMAT_VERSION {
+        return Err("Cache file is from a different version.".to_owned());
+    }
+    if file.u64()? != hash(source.as_bytes()) || file.u64()? != source.len() as u64 {
+        return Err("Cache file is for different source.".to_owned());
+    }
+    if file.u8()? != optimized as u8 {
+        return Err("Cache file was compiled with different options.".to_owned());
+    }
+    let length = file.u64()? as usize;
+    let checksum = file.u64()?;
+    let payload = file.take(length)?;
+    if hash(payload) != checksum || file.position != bytes.len() {
+        return Err("Cache file is damaged.".to_owned());
+    }
+    Reader {
+        bytes: payload,
+        position: 0,
+    }
+    .function()
+}
+
+// 64 bit FNV-1a.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    // Lengths and positions are saved as u32s. Anything bigger can't be
+    // cached.
+    fn len(&mut self, n: usize) -> Result<(), String> {
+        match u32::try_from(n) {
+            Ok(n) => {
+                self.u32(n);
+                Ok(())
+            }
+            Err(_) => Err(format!("Cannot save {} in a cache file.", n)),
+        }
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), String> {
+        self.len(s.len())?;
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), String> {
+        self.str(&function.name)?;
+        self.len(function.params.len())?;
+        for param in &function.params {
+            self.str(param)?;
+        }
+        self.len(function.locals.len())?;
+        for local in &function.locals {
+            self.str(local)?;
+        }
+        self.chunk(&function.chunk)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        self.len(chunk.code.len())?;
+        for op in &chunk.code {
+            self.op(*op);
+        }
+        for span in &chunk.spans {
+            self.position(span.start)?;
+            self.position(span.end)?;
+        }
+        self.len(chunk.constants.len())?;
+        for constant in &chunk.constants {
+            self.value(constant)?;
+        }
+        self.len(chunk.names.len())?;
+        for name in &chunk.names {
+            self.str(name)?;
+        }
+        self.len(chunk.functions.len())?;
+        for function in &chunk.functions {
+            self.function(function)?;
+        }
+        Ok(())
+    }
+
+    fn position(&mut self, position: Position) -> Result<(), String> {
+        self.len(position.offset)?;
+        self.len(position.line)?;
+        self.len(position.column)
+    }
+
+    fn op(&mut self, op: Op) {
+        let (tag, operands): (u8, &[u32]) = match op {
+            Op::Const(i) => (0, &[i]),
+            Op::GetLocal { depth, index } => (1, &[depth, index]),
+            Op::GetGlobal(i) => (2, &[i]),
+            Op::GetName(i) => (3, &[i]),
+            Op::DefineGlobal(i) => (4, &[i]),
+            Op::Bind(i) => (5, &[i]),
+            Op::EndScope => (6, &[]),
+            Op::Pop => (7, &[]),
+            Op::Jump(i) => (8, &[i]),
+            Op::JumpIfFalse(i) => (9, &[i]),
+            Op::Call(n) => (10, &[n]),
+            Op::TailCall(n) => (11, &[n]),
+            Op::Return => (12, &[]),
+            Op::Closure(i) => (13, &[i]),
+            Op::List(n) => (14, &[n]),
+            Op::Append(n) => (15, &[n]),
+            Op::Eval => (16, &[]),
+            Op::Fail(i) => (17, &[i]),
+            Op::Try(i) => (18, &[i]),
+            Op::EndTry => (19, &[]),
+            Op::Raise => (20, &[]),
+            Op::SetLocal { depth, index } => (21, &[depth, index]),
+            Op::SetGlobal(i) => (22, &[i]),
+            Op::SetName(i) => (23, &[i]),
+        };
+        self.u8(tag);
+        for operand in operands {
+            self.u32(*operand);
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Int(i) => {
+                self.u8(0);
+                self.u64(*i as u64);
+            }
+            Value::Float(x) => {
+                self.u8(1);
+                self.u64(x.to_bits());
+            }
+            Value::Boolean(b) => {
+                self.u8(2);
+                self.u8(*b as u8);
+            }
+            Value::Char(c) => {
+                self.u8(3);
+                self.u32(u32::from(*c));
+            }
+            Value::Str(s) => {
+                self.u8(4);
+                self.str(s)?;
+            }
+            Value::Symbol(s) => {
+                self.u8(5);
+                self.str(s)?;
+            }
+            Value::List(items) => {
+                self.u8(6);
+                self.len(items.len())?;
+                for item in items {
+                    self.value(item)?;
+                }
+            }
+            Value::Unit => self.u8(7),
+            _ => return Err(format!("Cannot save {:?} in a cache file.", value)),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let taken = &self.bytes[self.position..end];
+                self.position = end;
+                Ok(taken)
+            }
+            None => Err("Cache file ended early.".to_owned()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap_or_default()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    // Reads count things with read.
+    fn many<T>(
+        &mut self,
+        count: usize,
+        read: fn(&mut Reader<'a>) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        (0..count).map(|_| read(self)).collect()
+    }
+
+    fn function(&mut self) -> Result<Rc<Function>, String> {
+        let name = Rc::from(self.str()?);
+        let count = self.len()?;
+        let params = self.many(count, Reader::str)?;
+        let count = self.len()?;
+        let locals = self.many(count, Reader::str)?;
+        let chunk = self.chunk()?;
+        Ok(Rc::new(Function {
+            name,
+            params,
+            locals,
+            chunk,
+        }))
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let count = self.len()?;
+        let code = self.many(count, Reader::op)?;
+        let spans = self.many(count, Reader::span)?;
+        let count = self.len()?;
+        let constants = self.many(count, Reader::value)?;
+        let count = self.len()?;
+        let names = self.many(count, |reader| reader.str().map(Rc::from))?;
+        let count = self.len()?;
+        let functions = self.many(count, Reader::function)?;
+        let chunk = Chunk {
+            code,
+            spans,
+            constants,
+            names,
+            functions,
+        };
+        check_operands(&chunk)?;
+        Ok(chunk)
+    }
+
+    fn position(&mut self) -> Result<Position, String> {
+        Ok(Position {
+            offset: self.len()?,
+            line: self.len()?,
+            column: self.len()?,
+        })
+    }
+
+    fn span(&mut self) -> Result<Span, String> {
+        Ok(Span::new(self.position()?, self.position()?))
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        let op = match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::GetLocal {
+                depth: self.u32()?,
+                index: self.u32()?,
+            },
+            2 => Op::GetGlobal(self.u32()?),
+            3 => Op::GetName(self.u32()?),
+            4 => Op::DefineGlobal(self.u32()?),
+            5 => Op::Bind(self.u32()?),
+            6 => Op::EndScope,
+            7 => Op::Pop,
+            8 => Op::Jump(self.u32()?),
+            9 => Op::JumpIfFalse(self.u32()?),
+            10 => Op::Call(self.u32()?),
+            11 => Op::TailCall(self.u32()?),
+            12 => Op::Return,
+            13 => Op::Closure(self.u32()?),
+            14 => Op::List(self.u32()?),
+            15 => Op::Append(self.u32()?),
+            16 => Op::Eval,
+            17 => Op::Fail(self.u32()?),
+            18 => Op::Try(self.u32()?),
+            19 => Op::EndTry,
+            20 => Op::Raise,
+            21 => Op::SetLocal {
+                depth: self.u32()?,
+                index: self.u32()?,
+            },
+            22 => Op::SetGlobal(self.u32()?),
+            23 => Op::SetName(self.u32()?),
+            tag => return Err(format!("Unknown instruction {}.", tag)),
+        };
+        Ok(op)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let value = match self.u8()? {
+            0 => Value::Int(self.u64()? as i64),
+            1 => Value::Float(f64::from_bits(self.u64()?)),
+            2 => Value::Boolean(self.u8()? != 0),
+            3 => match char::from_u32(self.u32()?) {
+                Some(c) => Value::Char(c),
+                None => return Err("Invalid character.".to_owned()),
+            },
+            4 => Value::Str(self.str()?),
+            5 => Value::Symbol(self.str()?),
+            6 => {
+                let count = self.len()?;
+                Value::List(self.many(count, Reader::value)?.into())
+            }
+            7 => Value::Unit,
+            tag => return Err(format!("Unknown constant {}.", tag)),
+        };
+        Ok(value)
+    }
+}
+
+// The VM indexes a chunk's code, constants, names and functions with the
+// operands of its instructions without checking them, so a chunk whose
+// operands are out of range is rejected as damaged.
+fn check_operands(chunk: &Chunk) -> Result<(), String> {
+    for op in &chunk.code {
+        let (index, len) = match *op {
+            Op::Const(i) | Op::Fail(i) => (i, chunk.constants.len()),
+            Op::GetGlobal(i)
+            | Op::GetName(i)
+            | Op::SetGlobal(i)
+            | Op::SetName(i)
+            | Op::DefineGlobal(i)
+            | Op::Bind(i) => (i, chunk.names.len()),
+            Op::Closure(i) => (i, chunk.functions.len()),
+            Op::Jump(i) | Op::JumpIfFalse(i) | Op::Try(i) => (i, chunk.code.len()),
+            _ => continue,
+        };
+        if index as usize >= len {
+            return Err(format!(
+                "Cache file has an out of range operand in {:?}.",
+                op
+            ));
+        }
+    }
+    Ok(())
+}
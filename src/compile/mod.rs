@@ -0,0 +1,625 @@
+pub mod cache;
+pub mod vm;
+
+use crate::eval::builtins::{defined_name, expect_id_list, form_argument, TryForm};
+use crate::eval::environment::Environment;
+use crate::eval::error::{Arity, RuntimeError};
+use crate::eval::scope::internal_definitions;
+use crate::eval::value::Value;
+use crate::parser::ASTNode;
+use crate::span::Span;
+use std::rc::Rc;
+
+// An instruction for the virtual machine in vm.rs. The machine has a stack of
+// values which instructions push their results onto and pop their operands
+// off of. Operands which are known when compiling, like constants and names,
+// are stored in the Chunk and referred to by index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // Pushes constants[i].
+    Const(u32),
+    // Pushes a variable found by the resolver. See ASTNode::LocalRef.
+    GetLocal { depth: u32, index: u32 },
+    // Pushes the global named names[i].
+    GetGlobal(u32),
+    // Pushes the variable named names[i], searching local scopes first. Only
+    // used for identifiers the resolver hasn't seen.
+    GetName(u32),
+    // Pops a value and binds it globally to names[i].
+    DefineGlobal(u32),
+    // Pops a value and assigns it to a variable found by the resolver.
+    SetLocal { depth: u32, index: u32 },
+    // Pops a value and assigns it to the global named names[i], which must
+    // already be bound.
+    SetGlobal(u32),
+    // Pops a value and assigns it to the variable named names[i], searching
+    // local scopes first. Only used for identifiers the resolver hasn't seen.
+    SetName(u32),
+    // Pops a value and binds it to names[i] in a new scope.
+    Bind(u32),
+    // Leaves the innermost scope.
+    EndScope,
+    Pop,
+    // Continues from code[i].
+    Jump(u32),
+    // Pops a boolean and continues from code[i] if it's false.
+    JumpIfFalse(u32),
+    // Calls the function below the top n values with those values as its
+    // arguments, replacing them all with the result.
+    Call(u32),
+    // Like Call followed by Return, but reuses the current frame.
+    TailCall(u32),
+    Return,
+    // Pushes a closure over the current environment for functions[i].
+    Closure(u32),
+    // Pops n values and pushes a list of them.
+    List(u32),
+    // Pops n lists and pushes them joined into one.
+    Append(u32),
+    // Pops data representing code and evaluates it.
+    Eval,
+    // Fails with the message in constants[i].
+    Fail(u32),
+    // Until the matching EndTry, recovers from errors by unwinding to this
+    // frame and scope, pushing the error and continuing from code[i].
+    Try(u32),
+    EndTry,
+    // Pops an error pushed by Try and raises it again.
+    Raise,
+}
+
+// Compiled code along with the values it refers to.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    // The source location each instruction was compiled from, for errors.
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub names: Vec<Rc<str>>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+// The compiled form of a lambda, defun or top level program.
+#[derive(Debug)]
+pub struct Function {
+    // The name given by defun, "lambda", or for code which isn't a function
+    // in the program, "<program>" or "<eval>".
+    pub name: Rc<str>,
+    pub params: Vec<String>,
+    // The names the body defines, bound alongside params. See
+    // internal_definitions.
+    pub locals: Vec<String>,
+    pub chunk: Chunk,
+}
+
+// Compiles a resolved program, or a single top level form of one, which is
+// going to be run in env. The result returns the value of the last form.
+pub fn compile_program(ast: &ASTNode, env: &Environment) -> Rc<Function> {
+    let mut compiler = Compiler::new(env);
+    let statements = match ast {
+        ASTNode::Program { statements, .. } => statements.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    for (i, statement) in statements.iter().enumerate() {
+        if i > 0 {
+            compiler.emit(Op::Pop, statement.span());
+        }
+        compiler.compile(statement, false);
+    }
+    if statements.is_empty() {
+        compiler.emit_const(Value::Unit, ast.span());
+    }
+    compiler.emit(Op::Return, ast.span());
+    compiler.finish("<program>", Vec::new(), Vec::new())
+}
+
+// Compiles a resolved expression, as is done for the argument to eval.
+pub fn compile_expr(ast: &ASTNode, env: &Environment) -> Rc<Function> {
+    let mut compiler = Compiler::new(env);
+    compiler.compile(ast, true);
+    compiler.finish("<eval>", Vec::new(), Vec::new())
+}
+
+struct Compiler<'a> {
+    env: &'a Environment,
+    chunk: Chunk,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(env: &'a Environment) -> Compiler<'a> {
+        Compiler {
+            env,
+            chunk: Chunk::default(),
+        }
+    }
+
+    fn finish(self, name: &str, params: Vec<String>, locals: Vec<String>) -> Rc<Function> {
+        Rc::new(Function {
+            name: name.into(),
+            params,
+            locals,
+            chunk: self.chunk,
+        })
+    }
+
+    // Emits op and returns its index.
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn emit_const(&mut self, value: Value, span: Span) {
+        self.chunk.constants.push(value);
+        let index = self.chunk.constants.len() - 1;
+        self.emit(Op::Const(index as u32), span);
+    }
+
+    fn emit_fail(&mut self, message: &str, span: Span) {
+        self.chunk.constants.push(Value::Str(message.to_owned()));
+        let index = self.chunk.constants.len() - 1;
+        self.emit(Op::Fail(index as u32), span);
+    }
+
+    // Emits code failing with the same message as error.
+    fn emit_error(&mut self, error: RuntimeError, span: Span) {
+        self.emit_fail(&error.message(), span);
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        match self.chunk.names.iter().position(|n| &**n == name) {
+            Some(index) => index as u32,
+            None => {
+                self.chunk.names.push(Rc::from(name));
+                (self.chunk.names.len() - 1) as u32
+            }
+        }
+    }
+
+    // Points the jump at index to the next instruction emitted.
+    fn patch_jump(&mut self, index: usize) {
+        let target = self.chunk.code.len() as u32;
+        match &mut self.chunk.code[index] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::Try(to) => *to = target,
+            op => panic!("Tried to patch {:?} as a jump.", op),
+        }
+    }
+
+    // Emits code pushing the value of node. When tail is set node is the last
+    // thing evaluated in a function, so the code returns the value instead.
+    fn compile(&mut self, node: &ASTNode, tail: bool) {
+        match node {
+            ASTNode::Terminal { token, span } => match Value::parse(token) {
+                Ok(value) => self.emit_const(value, *span),
+                Err(e) => self.emit_error(e, *span),
+            },
+            ASTNode::LocalRef {
+                depth, index, span, ..
+            } => {
+                let op = Op::GetLocal {
+                    depth: *depth as u32,
+                    index: *index as u32,
+                };
+                self.emit(op, *span);
+            }
+            ASTNode::GlobalRef { name, span } => {
+                let name = self.name(name);
+                self.emit(Op::GetGlobal(name), *span);
+            }
+            ASTNode::Identifier { name, span } => {
+                let name = self.name(name);
+                self.emit(Op::GetName(name), *span);
+            }
+            // The empty list, (), evaluates to itself just like nil.
+            ASTNode::SExpr { children, span } if children.is_empty() => {
+                self.emit_const(Value::List(Default::default()), *span)
+            }
+            ASTNode::SExpr { children, span } => match self.special_form(children) {
+                Some(form) => {
+                    self.compile_special_form(form, &children[1..], *span, tail);
+                    // Special forms handle tail position themselves.
+                    return;
+                }
+                None => {
+                    for child in children.iter() {
+                        self.compile(child, false);
+                    }
+                    let argc = (children.len() - 1) as u32;
+                    let op = if tail {
+                        Op::TailCall(argc)
+                    } else {
+                        Op::Call(argc)
+                    };
+                    self.emit(op, *span);
+                    return;
+                }
+            },
+            ASTNode::Program { span, .. } => {
+                self.emit_fail("Found a program inside an expression.", *span)
+            }
+        }
+        if tail {
+            self.emit(Op::Return, node.span());
+        }
+    }
+
+    // If the s-expression children is a special form, returns its name.
+    fn special_form<'n>(&self, children: &'n [ASTNode]) -> Option<&'n str> {
+        let name = match &children[0] {
+            ASTNode::GlobalRef { name, .. } => &**name,
+            ASTNode::Identifier { name, .. } => name.as_str(),
+            _ => return None,
+        };
+        match self.env.get_global(name) {
+            Some(Value::LazyFunction(_)) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn compile_special_form(&mut self, form: &str, args: &[ASTNode], span: Span, tail: bool) {
+        match form {
+            "if" => return self.compile_if(args, span, tail),
+            "let" => return self.compile_let(args, span, tail),
+            "quote" => self.compile_quote(args, span),
+            "quasiquote" => self.compile_quasiquote(args, span),
+            "lambda" => self.compile_lambda(args, span),
+            "eval" => self.compile_eval(args, span),
+            "defun" => self.compile_defun(args, span),
+            "define" => self.compile_define(args, span),
+            "set!" => self.compile_set(args, span),
+            "try" => return self.compile_try(TryForm::parse(args), span, tail),
+            "unwind-protect" => {
+                return self.compile_try(TryForm::parse_unwind_protect(args), span, tail)
+            }
+            "unquote" => self.emit_fail("Found unquote (,) outside of a quasiquote.", span),
+            "unquote-splicing" => {
+                self.emit_fail("Found unquote-splicing (,@) outside of a quasiquote.", span)
+            }
+            _ => self.emit_fail(
+                &format!("The special form {:?} can't be compiled.", form),
+                span,
+            ),
+        }
+        if tail {
+            self.emit(Op::Return, span);
+        }
+    }
+
+    fn compile_if(&mut self, args: &[ASTNode], span: Span, tail: bool) {
+        // Expect (if <condition> <then> <else>)
+        if let Err(e) = RuntimeError::check_arity("if", Arity::Exactly(3), args) {
+            self.emit_error(e, span);
+            if tail {
+                self.emit(Op::Return, span);
+            }
+            return;
+        }
+        self.compile(&args[0], false);
+        let to_else = self.emit(Op::JumpIfFalse(0), args[0].span());
+        self.compile(&args[1], tail);
+        let to_end = if tail {
+            None
+        } else {
+            Some(self.emit(Op::Jump(0), span))
+        };
+        self.patch_jump(to_else);
+        self.compile(&args[2], tail);
+        if let Some(to_end) = to_end {
+            self.patch_jump(to_end);
+        }
+    }
+
+    fn compile_let(&mut self, args: &[ASTNode], span: Span, tail: bool) {
+        // Expect (let <Id> <Value> <body>)
+        match args {
+            [ASTNode::Identifier { name, .. }, value, body] => {
+                self.compile(value, false);
+                let name = self.name(name);
+                self.emit(Op::Bind(name), span);
+                self.compile(body, tail);
+                if !tail {
+                    self.emit(Op::EndScope, span);
+                }
+            }
+            _ => {
+                let error =
+                    RuntimeError::check_arity("let", Arity::Exactly(3), args).and_then(|_| {
+                        RuntimeError::malformed::<()>(&format!(
+                            "Could not bind identifier in let expression because \
+                             first argument was not an identifier. Found: {:?}",
+                            args[0]
+                        ))
+                    });
+                if let Err(e) = error {
+                    self.emit_error(e, span);
+                }
+                if tail {
+                    self.emit(Op::Return, span);
+                }
+            }
+        }
+    }
+
+    fn compile_quote(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (quote <expr>)
+        if let Err(e) = RuntimeError::check_arity("quote", Arity::Exactly(1), args) {
+            return self.emit_error(e, span);
+        }
+        match Value::from_ast(&args[0]) {
+            Ok(value) => self.emit_const(value, span),
+            Err(e) => self.emit_error(e, span),
+        }
+    }
+
+    fn compile_quasiquote(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (quasiquote <template>)
+        if let Err(e) = RuntimeError::check_arity("quasiquote", Arity::Exactly(1), args) {
+            return self.emit_error(e, span);
+        }
+        if form_argument(&args[0], "unquote-splicing").is_some() {
+            return self.emit_fail("Found ,@ outside of a list in quasiquote.", span);
+        }
+        self.compile_template(&args[0], 1);
+    }
+
+    // Emits code building the data described by a quasiquote template. This
+    // does the same as builtins::expand_quasiquote but at compile time.
+    fn compile_template(&mut self, template: &ASTNode, depth: usize) {
+        let span = template.span();
+        if let Some(inner) = form_argument(template, "unquote") {
+            if depth == 1 {
+                self.compile(inner, false);
+            } else {
+                self.emit_const(Value::Symbol("unquote".to_owned()), span);
+                self.compile_template(inner, depth - 1);
+                self.emit(Op::List(2), span);
+            }
+            return;
+        }
+        if let Some(inner) = form_argument(template, "quasiquote") {
+            self.emit_const(Value::Symbol("quasiquote".to_owned()), span);
+            self.compile_template(inner, depth + 1);
+            self.emit(Op::List(2), span);
+            return;
+        }
+        let children = match template {
+            ASTNode::SExpr { children, .. } => children,
+            _ => {
+                return match Value::from_ast(template) {
+                    Ok(value) => self.emit_const(value, span),
+                    Err(e) => self.emit_error(e, span),
+                }
+            }
+        };
+        // Runs of ordinary elements are gathered into lists, which are then
+        // appended to the spliced in lists.
+        let mut run_length = 0;
+        let mut parts = 0;
+        for child in children.iter() {
+            match form_argument(child, "unquote-splicing") {
+                Some(inner) if depth == 1 => {
+                    if run_length > 0 {
+                        self.emit(Op::List(run_length), span);
+                        parts += 1;
+                        run_length = 0;
+                    }
+                    self.compile(inner, false);
+                    parts += 1;
+                }
+                Some(inner) => {
+                    self.emit_const(Value::Symbol("unquote-splicing".to_owned()), span);
+                    self.compile_template(inner, depth - 1);
+                    self.emit(Op::List(2), span);
+                    run_length += 1;
+                }
+                None => {
+                    self.compile_template(child, depth);
+                    run_length += 1;
+                }
+            }
+        }
+        if parts == 0 {
+            self.emit(Op::List(run_length), span);
+        } else {
+            if run_length > 0 {
+                self.emit(Op::List(run_length), span);
+                parts += 1;
+            }
+            self.emit(Op::Append(parts), span);
+        }
+    }
+
+    // Compiles the parameter list and body of a function, failing if the
+    // parameter list isn't a list of identifiers.
+    fn compile_function(
+        &mut self,
+        name: &str,
+        params: &ASTNode,
+        body: &[ASTNode],
+        span: Span,
+    ) -> Result<u32, RuntimeError> {
+        let params = expect_id_list(params)?;
+        let mut compiler = Compiler::new(self.env);
+        if body.is_empty() {
+            compiler.emit_fail("Function body is empty.", span);
+            compiler.emit(Op::Return, span);
+        }
+        compiler.compile_sequence(body, true);
+        let locals = internal_definitions(body);
+        self.chunk
+            .functions
+            .push(compiler.finish(name, params, locals));
+        Ok((self.chunk.functions.len() - 1) as u32)
+    }
+
+    // Compiles exprs to be evaluated in turn, leaving the value of the last.
+    fn compile_sequence(&mut self, exprs: &[ASTNode], tail: bool) {
+        if let Some((last, init)) = exprs.split_last() {
+            for expr in init {
+                self.compile(expr, false);
+                self.emit(Op::Pop, expr.span());
+            }
+            self.compile(last, tail);
+        }
+    }
+
+    // Compiles exprs for their side effects alone.
+    fn compile_effects(&mut self, exprs: &[ASTNode]) {
+        for expr in exprs {
+            self.compile(expr, false);
+            self.emit(Op::Pop, expr.span());
+        }
+    }
+
+    fn compile_try(&mut self, form: Result<TryForm, RuntimeError>, span: Span, tail: bool) {
+        let form = match form {
+            Ok(form) => form,
+            Err(e) => {
+                self.emit_error(e, span);
+                if tail {
+                    self.emit(Op::Return, span);
+                }
+                return;
+            }
+        };
+        // The body can't be in tail position, as its frame must stay to
+        // catch errors.
+        let to_handler = self.emit(Op::Try(0), span);
+        self.compile_sequence(form.body, false);
+        self.emit(Op::EndTry, span);
+        let mut to_cleanup = vec![self.emit(Op::Jump(0), span)];
+        self.patch_jump(to_handler);
+        match (form.catch, form.finally) {
+            (Some((name, handler)), None) => {
+                let name = self.name(name);
+                self.emit(Op::Bind(name), span);
+                self.compile_sequence(handler, tail);
+                if !tail {
+                    self.emit(Op::EndScope, span);
+                }
+            }
+            // Errors from the handler still run the cleanup before going on.
+            (Some((name, handler)), Some(cleanup)) => {
+                let name = self.name(name);
+                self.emit(Op::Bind(name), span);
+                let to_rethrow = self.emit(Op::Try(0), span);
+                self.compile_sequence(handler, false);
+                self.emit(Op::EndTry, span);
+                self.emit(Op::EndScope, span);
+                to_cleanup.push(self.emit(Op::Jump(0), span));
+                self.patch_jump(to_rethrow);
+                self.emit(Op::EndScope, span);
+                self.compile_effects(cleanup);
+                self.emit(Op::Raise, span);
+            }
+            (None, cleanup) => {
+                self.compile_effects(cleanup.unwrap_or_default());
+                self.emit(Op::Raise, span);
+            }
+        }
+        for jump in to_cleanup {
+            self.patch_jump(jump);
+        }
+        if let Some(cleanup) = form.finally {
+            self.compile_effects(cleanup);
+        }
+        if tail {
+            self.emit(Op::Return, span);
+        }
+    }
+
+    fn compile_lambda(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (lambda (<Id>...) <body>...)
+        let function = RuntimeError::check_arity("lambda", Arity::AtLeast(1), args)
+            .and_then(|_| self.compile_function("lambda", &args[0], &args[1..], span));
+        match function {
+            Ok(function) => {
+                self.emit(Op::Closure(function), span);
+            }
+            Err(e) => self.emit_error(e, span),
+        }
+    }
+
+    fn compile_defun(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (defun <Id> (<Id>...) <body>...)
+        let function = RuntimeError::check_arity("defun", Arity::AtLeast(2), args)
+            .and_then(|_| defined_name("defun", &args[0]))
+            .and_then(|name| self.compile_function(name, &args[1], &args[2..], span));
+        match function {
+            Ok(function) => {
+                self.emit(Op::Closure(function), span);
+                self.emit_definition(&args[0], span);
+            }
+            Err(e) => self.emit_error(e, span),
+        }
+    }
+
+    fn compile_define(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (define <Id> <Value>)
+        let name = RuntimeError::check_arity("define", Arity::Exactly(2), args)
+            .and_then(|_| defined_name("define", &args[0]));
+        match name {
+            Ok(_) => {
+                self.compile(&args[1], false);
+                self.emit_definition(&args[0], span);
+            }
+            Err(e) => self.emit_error(e, span),
+        }
+    }
+
+    // Emits code binding name, checked by defined_name, to the value on top
+    // of the stack and pushing unit in its place.
+    fn emit_definition(&mut self, name: &ASTNode, span: Span) {
+        match name {
+            ASTNode::LocalRef { depth, index, .. } => {
+                let op = Op::SetLocal {
+                    depth: *depth as u32,
+                    index: *index as u32,
+                };
+                self.emit(op, name.span());
+            }
+            ASTNode::Identifier { name, .. } => {
+                let name = self.name(name);
+                self.emit(Op::DefineGlobal(name), span);
+            }
+            _ => {}
+        }
+        self.emit_const(Value::Unit, span);
+    }
+
+    fn compile_set(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (set! <Id> <Value>)
+        if let Err(e) = RuntimeError::check_arity("set!", Arity::Exactly(2), args) {
+            return self.emit_error(e, span);
+        }
+        let op = match &args[0] {
+            ASTNode::LocalRef { depth, index, .. } => Op::SetLocal {
+                depth: *depth as u32,
+                index: *index as u32,
+            },
+            ASTNode::GlobalRef { name, .. } => Op::SetGlobal(self.name(name)),
+            ASTNode::Identifier { name, .. } => Op::SetName(self.name(name)),
+            other => {
+                return self.emit_fail(
+                    &format!(
+                        "Expected identifier as first argument to set!. Found {:?}",
+                        other
+                    ),
+                    span,
+                )
+            }
+        };
+        self.compile(&args[1], false);
+        self.emit(op, args[0].span());
+        self.emit_const(Value::Unit, span);
+    }
+
+    fn compile_eval(&mut self, args: &[ASTNode], span: Span) {
+        // Expect (eval <expr>)
+        if let Err(e) = RuntimeError::check_arity("eval", Arity::Exactly(1), args) {
+            return self.emit_error(e, span);
+        }
+        self.compile(&args[0], false);
+        self.emit(Op::Eval, args[0].span());
+    }
+}
@@ -0,0 +1,424 @@
+use super::{compile_expr, Function, Op};
+use crate::eval::environment::{Context, Environment};
+use crate::eval::error::{Arity, ErrorKind, RuntimeError, TraceFrame};
+use crate::eval::value::{Callable, Value};
+use crate::eval::{
+    code_from_data, expect_assigned, expect_bound, run_step, RuspResult, Step, StepResult,
+};
+use crate::span::Span;
+use std::any::Any;
+use std::rc::Rc;
+
+// A function compiled to bytecode along with the environment it was created
+// in.
+pub struct Closure {
+    function: Rc<Function>,
+    env: Environment,
+}
+
+impl Closure {
+    // Returns the environment for a call to this closure with args.
+    fn bind(&self, args: Vec<Value>) -> Result<Environment, RuntimeError> {
+        let params = &self.function.params;
+        RuntimeError::check_arity(&self.function.name, Arity::Exactly(params.len()), &args)?;
+        // The names the body defines can't be read until it defines them.
+        Ok(self.env.extend_defining(
+            params.iter().cloned().zip(args).collect(),
+            &self.function.locals,
+        ))
+    }
+}
+
+impl Callable for Closure {
+    // Only used when something other than the VM calls the closure, so the
+    // call runs on a VM of its own.
+    fn invoke(&self, ctx: &mut Context, args: &[Value]) -> StepResult {
+        let mut vm = Vm::new();
+        vm.call_closure(ctx, self, args.to_vec(), None)?;
+        vm.run(ctx).map(Step::Done)
+    }
+
+    fn name(&self) -> Rc<str> {
+        self.function.name.clone()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// A call which hasn't returned yet.
+struct Frame {
+    function: Rc<Function>,
+    // The index of the next instruction to run.
+    ip: usize,
+    env: Environment,
+    // Where this call's values start on the stack.
+    base: usize,
+    // Where the function was called from, for backtraces. None for frames
+    // which aren't calls in the program, like the program itself or eval.
+    call_site: Option<Span>,
+}
+
+// Where to recover from an error, set up by Op::Try.
+struct Handler {
+    // The number of frames when the handler was set up, so the last is the
+    // one to continue in.
+    frames: usize,
+    // The size of the stack and the environment when the handler was set up.
+    stack: usize,
+    env: Environment,
+    // Where to continue from.
+    ip: usize,
+}
+
+// Runs function, compiled by compile_program, in env.
+pub fn run(env: &Environment, ctx: &mut Context, function: Rc<Function>) -> RuspResult {
+    let mut vm = Vm::new();
+    vm.push_frame(ctx, function, env.clone(), None)?;
+    vm.run(ctx)
+}
+
+struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    // Innermost last.
+    handlers: Vec<Handler>,
+}
+
+impl Vm {
+    fn new() -> Vm {
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+        }
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames
+            .last()
+            .expect("the VM has a frame while running")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("the VM has a frame while running")
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("the VM stack has a value to pop")
+    }
+
+    fn push_frame(
+        &mut self,
+        ctx: &Context,
+        function: Rc<Function>,
+        env: Environment,
+        call_site: Option<Span>,
+    ) -> Result<(), RuntimeError> {
+        if self.frames.len() >= ctx.max_depth {
+            return RuntimeError::new(ErrorKind::StackOverflow {
+                max_depth: ctx.max_depth,
+            });
+        }
+        self.frames.push(Frame {
+            function,
+            ip: 0,
+            env,
+            base: self.stack.len(),
+            call_site,
+        });
+        Ok(())
+    }
+
+    // Runs until the outermost frame returns, and returns its value.
+    fn run(&mut self, ctx: &mut Context) -> RuspResult {
+        loop {
+            let frame = self.frame_mut();
+            let ip = frame.ip;
+            frame.ip += 1;
+            let op = frame.function.chunk.code[ip];
+            match self.execute(ctx, op) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {}
+                Err(e) => {
+                    // The frame which failed is the innermost one left.
+                    let span = self.frame().function.chunk.spans[ip];
+                    let e = e.or_at(span);
+                    match self.handlers.pop() {
+                        Some(handler) => self.recover(e, handler),
+                        None => return Err(e.with_backtrace(self.backtrace())),
+                    }
+                }
+            }
+        }
+    }
+
+    // Unwinds to handler and continues from there with error on the stack.
+    fn recover(&mut self, error: RuntimeError, handler: Handler) {
+        let unwound = self.frames.split_off(handler.frames);
+        let calls = unwound.iter().rev().filter_map(|frame| {
+            frame.call_site.map(|call_site| TraceFrame {
+                name: frame.function.name.clone(),
+                call_site,
+            })
+        });
+        let error = error.with_backtrace(calls);
+        self.stack.truncate(handler.stack);
+        self.stack.push(Value::Error(Rc::new(error)));
+        let frame = self.frame_mut();
+        frame.env = handler.env;
+        frame.ip = handler.ip;
+    }
+
+    // The calls in progress, innermost first.
+    fn backtrace(&self) -> impl Iterator<Item = TraceFrame> + '_ {
+        self.frames.iter().rev().filter_map(|frame| {
+            frame.call_site.map(|call_site| TraceFrame {
+                name: frame.function.name.clone(),
+                call_site,
+            })
+        })
+    }
+
+    // The source location of the instruction the current frame is running.
+    fn current_span(&self) -> Span {
+        let frame = self.frame();
+        frame.function.chunk.spans[frame.ip - 1]
+    }
+
+    // Runs op. Returns the result of the program once the outermost frame
+    // returns.
+    fn execute(&mut self, ctx: &mut Context, op: Op) -> Result<Option<Value>, RuntimeError> {
+        match op {
+            Op::Const(i) => {
+                let value = self.frame().function.chunk.constants[i as usize].clone();
+                self.stack.push(value);
+            }
+            Op::GetLocal { depth, index } => {
+                let value = self.frame().env.get_local(depth as usize, index as usize)?;
+                let value = match value {
+                    Some(value) => value,
+                    None => return Err("Found a variable in an unknown scope.".to_owned().into()),
+                };
+                self.stack.push(value);
+            }
+            Op::GetGlobal(i) => {
+                let frame = self.frame();
+                let name = &frame.function.chunk.names[i as usize];
+                let value = expect_bound(frame.env.get_global(name), name)?;
+                self.stack.push(value);
+            }
+            Op::GetName(i) => {
+                let frame = self.frame();
+                let name = &frame.function.chunk.names[i as usize];
+                let value = expect_bound(frame.env.get(name)?, name)?;
+                self.stack.push(value);
+            }
+            Op::DefineGlobal(i) => {
+                let value = self.pop();
+                let frame = self.frame();
+                frame
+                    .env
+                    .define(&frame.function.chunk.names[i as usize], value);
+            }
+            Op::SetLocal { depth, index } => {
+                let value = self.pop();
+                if !self
+                    .frame()
+                    .env
+                    .set_local(depth as usize, index as usize, value)
+                {
+                    return Err("Found a variable in an unknown scope.".to_owned().into());
+                }
+            }
+            Op::SetGlobal(i) => {
+                let value = self.pop();
+                let frame = self.frame();
+                let name = &frame.function.chunk.names[i as usize];
+                expect_assigned(frame.env.set_global(name, value), name)?;
+            }
+            Op::SetName(i) => {
+                let value = self.pop();
+                let frame = self.frame();
+                let name = &frame.function.chunk.names[i as usize];
+                expect_assigned(frame.env.set(name, value), name)?;
+            }
+            Op::Bind(i) => {
+                let value = self.pop();
+                let frame = self.frame_mut();
+                let name = &frame.function.chunk.names[i as usize];
+                frame.env = frame.env.extend(name, value);
+            }
+            Op::EndScope => {
+                let frame = self.frame_mut();
+                frame.env = frame.env.outer();
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Jump(to) => self.frame_mut().ip = to as usize,
+            Op::JumpIfFalse(to) => match self.pop() {
+                Value::Boolean(true) => {}
+                Value::Boolean(false) => self.frame_mut().ip = to as usize,
+                other => return RuntimeError::type_mismatch("if", "a bool", &other),
+            },
+            Op::Call(argc) => self.call(ctx, argc as usize)?,
+            Op::TailCall(argc) => return self.tail_call(ctx, argc as usize),
+            Op::Return => {
+                let value = self.pop();
+                let frame = self
+                    .frames
+                    .pop()
+                    .expect("the VM has a frame to return from");
+                self.stack.truncate(frame.base);
+                if self.frames.is_empty() {
+                    return Ok(Some(value));
+                }
+                self.stack.push(value);
+            }
+            Op::Closure(i) => {
+                let frame = self.frame();
+                let closure = Closure {
+                    function: frame.function.chunk.functions[i as usize].clone(),
+                    env: frame.env.clone(),
+                };
+                self.stack.push(Value::Closure(Rc::new(closure)));
+            }
+            Op::List(n) => {
+                let items = self.stack.split_off(self.stack.len() - n as usize);
+                self.stack.push(Value::List(items.into()));
+            }
+            Op::Append(n) => {
+                let mut lst = Vec::new();
+                for part in self.stack.split_off(self.stack.len() - n as usize) {
+                    match part {
+                        Value::List(items) => lst.extend(items.into_vec()),
+                        other => {
+                            return RuntimeError::type_mismatch(
+                                "unquote-splicing",
+                                "a list",
+                                &other,
+                            )
+                        }
+                    }
+                }
+                self.stack.push(Value::List(lst.into()));
+            }
+            Op::Eval => {
+                let span = self.current_span();
+                let env = self.frame().env.clone();
+                let data = self.pop();
+                let code = code_from_data(&data, &env, ctx, span)?;
+                let function = compile_expr(&code, &env);
+                self.push_frame(ctx, function, env, None)?;
+            }
+            Op::Try(to) => {
+                let handler = Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    env: self.frame().env.clone(),
+                    ip: to as usize,
+                };
+                self.handlers.push(handler);
+            }
+            Op::EndTry => {
+                self.handlers.pop();
+            }
+            Op::Raise => {
+                return match self.pop() {
+                    Value::Error(error) => Err(RuntimeError::clone(&error)),
+                    value => RuntimeError::new(ErrorKind::UserRaised { value }),
+                }
+            }
+            Op::Fail(i) => {
+                return match &self.frame().function.chunk.constants[i as usize] {
+                    Value::Str(message) => RuntimeError::malformed(message),
+                    other => RuntimeError::malformed(&format!("{:?}", other)),
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    // Calls the function below the top argc values on the stack.
+    fn call(&mut self, ctx: &mut Context, argc: usize) -> Result<(), RuntimeError> {
+        let args = self.stack.split_off(self.stack.len() - argc);
+        let func = self.pop();
+        let env = self.frame().env.clone();
+        self.call_value(ctx, &env, func, args)
+    }
+
+    // Like call, but a call to a compiled closure replaces the current frame
+    // rather than adding one. Anything else is called and returned from.
+    fn tail_call(&mut self, ctx: &mut Context, argc: usize) -> Result<Option<Value>, RuntimeError> {
+        let args = self.stack.split_off(self.stack.len() - argc);
+        let func = self.pop();
+        if let Some(closure) = as_compiled(&func) {
+            let env = closure.bind(args)?;
+            let call_site = self.current_span();
+            let frame = self.frame_mut();
+            frame.function = closure.function.clone();
+            frame.ip = 0;
+            frame.env = env;
+            frame.call_site = Some(call_site);
+            let base = frame.base;
+            self.stack.truncate(base);
+            return Ok(None);
+        }
+        let env = self.frame().env.clone();
+        self.call_value(ctx, &env, func, args)?;
+        self.execute(ctx, Op::Return)
+    }
+
+    // Calls func with args, either pushing a new frame or pushing the result.
+    fn call_value(
+        &mut self,
+        ctx: &mut Context,
+        env: &Environment,
+        func: Value,
+        args: Vec<Value>,
+    ) -> Result<(), RuntimeError> {
+        match func {
+            Value::Closure(closure) => match closure.as_any().downcast_ref::<Closure>() {
+                Some(compiled) => {
+                    let call_site = self.current_span();
+                    self.call_closure(ctx, compiled, args, Some(call_site))
+                }
+                // A closure made by the tree walking evaluator.
+                None => {
+                    let step = closure.invoke(ctx, &args)?;
+                    let value = run_step(ctx, step)?;
+                    self.stack.push(value);
+                    Ok(())
+                }
+            },
+            Value::Function(func) => {
+                let value = func(env, ctx, &args)?;
+                self.stack.push(value);
+                Ok(())
+            }
+            _ => RuntimeError::new(ErrorKind::NotCallable { found: func }),
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        ctx: &mut Context,
+        closure: &Closure,
+        args: Vec<Value>,
+        call_site: Option<Span>,
+    ) -> Result<(), RuntimeError> {
+        let env = closure.bind(args)?;
+        self.push_frame(ctx, closure.function.clone(), env, call_site)
+    }
+}
+
+fn as_compiled(value: &Value) -> Option<&Closure> {
+    match value {
+        Value::Closure(closure) => closure.as_any().downcast_ref::<Closure>(),
+        _ => None,
+    }
+}
@@ -0,0 +1,147 @@
+use crate::lexer::{SpannedToken, Token, Trivia};
+use crate::parser::ASTNode;
+use crate::span::Span;
+
+// A concrete syntax tree. Unlike ASTNode this keeps every character of the
+// source, including whitespace, comments and the exact spelling of each
+// token, so it can be printed back out byte for byte. This makes it the tree
+// to use for tooling like formatters and syntax highlighters.
+#[derive(Debug, Clone)]
+pub enum CstNode {
+    // A single token such as an identifier or literal.
+    Atom(SpannedToken),
+    // '(' children ')'.
+    List {
+        open: SpannedToken,
+        children: Vec<CstNode>,
+        close: SpannedToken,
+    },
+    // A reader shorthand and the expression it applies to, e.g. 'x.
+    Prefixed {
+        prefix: SpannedToken,
+        expr: Box<CstNode>,
+    },
+}
+
+// All of the top level forms in some source, along with any trivia after the
+// last of them.
+#[derive(Debug, Clone)]
+pub struct Cst {
+    pub forms: Vec<CstNode>,
+    pub trailing_trivia: Vec<Trivia>,
+}
+
+fn write_trivia(trivia: &[Trivia], out: &mut String) {
+    for t in trivia {
+        out.push_str(&t.text);
+    }
+}
+
+fn write_token(token: &SpannedToken, out: &mut String) {
+    write_trivia(&token.leading_trivia, out);
+    out.push_str(&token.text);
+}
+
+// The special form a reader shorthand like 'x expands to.
+pub fn reader_macro_name(token: &Token) -> Option<&'static str> {
+    match token {
+        Token::Quote => Some("quote"),
+        Token::Quasiquote => Some("quasiquote"),
+        Token::Unquote => Some("unquote"),
+        Token::UnquoteSplicing => Some("unquote-splicing"),
+        _ => None,
+    }
+}
+
+impl CstNode {
+    // The span of the node itself, not including its leading trivia.
+    pub fn span(&self) -> Span {
+        match self {
+            CstNode::Atom(token) => token.span,
+            CstNode::List { open, close, .. } => open.span.to(close.span),
+            CstNode::Prefixed { prefix, expr } => prefix.span.to(expr.span()),
+        }
+    }
+
+    // Appends the source text of the node, including its leading trivia, to
+    // out.
+    pub fn write_source(&self, out: &mut String) {
+        match self {
+            CstNode::Atom(token) => write_token(token, out),
+            CstNode::List {
+                open,
+                children,
+                close,
+            } => {
+                write_token(open, out);
+                for child in children {
+                    child.write_source(out);
+                }
+                write_token(close, out);
+            }
+            CstNode::Prefixed { prefix, expr } => {
+                write_token(prefix, out);
+                expr.write_source(out);
+            }
+        }
+    }
+
+    // Drops the trivia and token spellings to get the tree the evaluator
+    // works with.
+    pub fn to_ast(&self) -> ASTNode {
+        match self {
+            CstNode::Atom(SpannedToken {
+                token: Token::Id(name),
+                span,
+                ..
+            }) => ASTNode::Identifier {
+                name: name.to_owned(),
+                span: *span,
+            },
+            CstNode::Atom(SpannedToken { token, span, .. }) => ASTNode::Terminal {
+                token: token.clone(),
+                span: *span,
+            },
+            CstNode::List { children, .. } => ASTNode::SExpr {
+                children: children.iter().map(CstNode::to_ast).collect(),
+                span: self.span(),
+            },
+            // 'x is read as (quote x) and likewise for the other shorthands.
+            CstNode::Prefixed { prefix, expr } => ASTNode::SExpr {
+                children: vec![
+                    ASTNode::Identifier {
+                        name: reader_macro_name(&prefix.token)
+                            .unwrap_or_default()
+                            .to_owned(),
+                        span: prefix.span,
+                    },
+                    expr.to_ast(),
+                ]
+                .into(),
+                span: self.span(),
+            },
+        }
+    }
+}
+
+impl Cst {
+    // Prints the tree back out. This is exactly the source it was parsed
+    // from.
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        for form in &self.forms {
+            form.write_source(&mut out);
+        }
+        write_trivia(&self.trailing_trivia, &mut out);
+        out
+    }
+
+    pub fn to_ast(&self) -> ASTNode {
+        let statements: Vec<ASTNode> = self.forms.iter().map(CstNode::to_ast).collect();
+        let span = match (statements.first(), statements.last()) {
+            (Some(first), Some(last)) => first.span().to(last.span()),
+            _ => Span::default(),
+        };
+        ASTNode::Program { statements, span }
+    }
+}
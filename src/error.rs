@@ -1,55 +1,125 @@
-use crate::eval::error::RuntimeError;
+use crate::eval::error::{Backtrace, RuntimeError};
+use crate::span::Span;
 use std::fmt;
+use std::rc::Rc;
 
 pub struct InterpreterError {
-    kind: String,
+    kind: &'static str,
     message: String,
+    // Boxed, like backtrace, to keep errors small.
+    span: Option<Box<Span>>,
+    file: Option<Rc<str>>,
+    // The calls in progress when a runtime error happened.
+    backtrace: Box<Backtrace>,
+    // Further errors found in the same input, e.g. every syntax error in a
+    // file rather than just the first.
+    others: Vec<InterpreterError>,
 }
 
 impl InterpreterError {
-    pub fn new(kind: &str, message: &str) -> InterpreterError {
+    pub fn new(kind: &'static str, message: &str) -> InterpreterError {
         InterpreterError {
-            kind: String::from(kind),
+            kind,
             message: String::from(message),
+            span: None,
+            file: None,
+            backtrace: Box::default(),
+            others: Vec::new(),
+        }
+    }
+
+    pub fn at(mut self, span: Span) -> InterpreterError {
+        self.span = Some(Box::new(span));
+        self
+    }
+
+    // Names the file the error came from so it can be reported as
+    // file:line:col.
+    pub fn in_file(self, file: &str) -> InterpreterError {
+        self.in_shared_file(Rc::from(file))
+    }
+
+    fn in_shared_file(mut self, file: Rc<str>) -> InterpreterError {
+        self.others = self
+            .others
+            .into_iter()
+            .map(|e| e.in_shared_file(file.clone()))
+            .collect();
+        self.file = Some(file);
+        self
+    }
+
+    // Reports other alongside this error.
+    pub fn and(mut self, other: InterpreterError) -> InterpreterError {
+        self.others.push(other);
+        self
+    }
+
+    fn location(&self) -> Option<String> {
+        match (&self.file, &self.span) {
+            (_, Some(span)) => Some(self.locate(span)),
+            (Some(file), None) => Some(file.to_string()),
+            (None, None) => None,
+        }
+    }
+
+    fn locate(&self, span: &Span) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, span),
+            None => span.to_string(),
         }
     }
 }
 
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Encountered {}.\nMessage: {}", self.kind, self.message)
+        match self.location() {
+            Some(location) => write!(f, "Encountered {} at {}.", self.kind, location)?,
+            None => write!(f, "Encountered {}.", self.kind)?,
+        }
+        write!(f, "\nMessage: {}", self.message)?;
+        if !self.backtrace.calls.is_empty() {
+            write!(f, "\nBacktrace (innermost call first):")?;
+            for call in &self.backtrace.calls {
+                let call_site = self.locate(&call.call_site);
+                write!(f, "\n    in {}, called at {}", call.name, call_site)?;
+            }
+            if self.backtrace.omitted > 0 {
+                write!(f, "\n    ... and {} more", self.backtrace.omitted)?;
+            }
+        }
+        for other in &self.others {
+            write!(f, "\n{}", other)?;
+        }
+        Ok(())
     }
 }
 
 impl fmt::Debug for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Encountered {}.\nMessage: {}", self.kind, self.message)
+        fmt::Display::fmt(self, f)
     }
 }
 
 impl From<String> for InterpreterError {
     fn from(s: String) -> InterpreterError {
-        InterpreterError {
-            kind: "Generic Error".to_owned(),
-            message: s,
-        }
+        InterpreterError::new("Generic Error", &s)
     }
 }
 
 impl From<std::io::Error> for InterpreterError {
     fn from(err: std::io::Error) -> InterpreterError {
-        InterpreterError {
-            kind: String::from("io"),
-            message: err.to_string(),
-        }
+        InterpreterError::new("io", &err.to_string())
     }
 }
 
 impl From<RuntimeError> for InterpreterError {
     fn from(err: RuntimeError) -> Self {
-        InterpreterError {
-            kind: String::from("RuntimeError"),
-            message: err.message,
+        let mut interpreter_error = InterpreterError::new("RuntimeError", &err.message());
+        interpreter_error.backtrace = err.backtrace;
+        match err.span {
+            Some(span) => interpreter_error.at(*span),
+            None => interpreter_error,
         }
     }
 }
@@ -1,75 +1,108 @@
 use super::environment::{Context, Environment};
-use super::error::RuntimeError;
-use super::RuspResult;
-use crate::eval::eval;
+use super::error::{Arity, ErrorKind, RuntimeError};
+use super::expander::{expand_fully, expand_once};
+use super::scope::internal_definitions;
+use super::{code_from_data, eval_each, expect_assigned, RuspResult, Step, StepResult};
 use crate::eval::value::Callable;
 use crate::eval::value::Value;
 use crate::parser::ASTNode;
 
-use text_io::read;
+use text_io::try_read;
 
 pub fn readline(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
-    assert!(args.is_empty());
-    Ok(Value::Str(read!("{}\n")))
+    RuntimeError::check_arity("readline", Arity::Exactly(0), args)?;
+    let line: Result<String, _> = try_read!("{}\n");
+    line.map(Value::Str).map_err(|e| {
+        RuntimeError::new_err(ErrorKind::IO {
+            message: format!("Could not read a line from stdin: {}", e),
+        })
+    })
 }
 
 pub fn plus(_env: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
     // Called like: (+ 1 2)
-    assert!(args.len() == 2);
-    binary_int_func("+", &args[0], &args[1], |x: i64, y: i64| x + y)
+    RuntimeError::check_arity("+", Arity::Exactly(2), args)?;
+    binary_numeric_func("+", &args[0], &args[1], i64::checked_add, |x, y| x + y)
 }
 
 pub fn minus(_env: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
-    // Called like: (+ 1 2)
-    assert!(args.len() == 2);
-    binary_int_func("-", &args[0], &args[1], |x: i64, y: i64| x - y)
+    // Called like: (- 1 2)
+    RuntimeError::check_arity("-", Arity::Exactly(2), args)?;
+    binary_numeric_func("-", &args[0], &args[1], i64::checked_sub, |x, y| x - y)
+}
+
+// Returns v as a float if it is a number.
+fn as_float(v: &Value) -> Option<f64> {
+    match v {
+        Value::Int(i) => Some(*i as f64),
+        Value::Float(x) => Some(*x),
+        _ => None,
+    }
 }
 
-fn binary_int_func(name: &str, lhs: &Value, rhs: &Value, func: fn(i64, i64) -> i64) -> RuspResult {
+// Applies int_func if both args are ints, which returns None on overflow. If
+// either is a float, both are converted to floats and float_func is applied
+// instead.
+fn binary_numeric_func(
+    name: &str,
+    lhs: &Value,
+    rhs: &Value,
+    int_func: fn(i64, i64) -> Option<i64>,
+    float_func: fn(f64, f64) -> f64,
+) -> RuspResult {
     match (lhs, rhs) {
-        (Value::Int(lhs_val), Value::Int(rhs_val)) => Ok(Value::Int(func(*lhs_val, *rhs_val))),
-        _ => RuntimeError::new(&format!(
-            "Expected both args to '{}' to be integers. \
-                 Found {:?} and {:?}.",
-            name, lhs, rhs
-        )),
+        (Value::Int(lhs_val), Value::Int(rhs_val)) => match int_func(*lhs_val, *rhs_val) {
+            Some(result) => Ok(Value::Int(result)),
+            None => RuntimeError::new(ErrorKind::Overflow {
+                name: name.to_owned(),
+                lhs: *lhs_val,
+                rhs: *rhs_val,
+            }),
+        },
+        _ => match (as_float(lhs), as_float(rhs)) {
+            (Some(lhs_val), Some(rhs_val)) => Ok(Value::Float(float_func(lhs_val, rhs_val))),
+            (None, _) => RuntimeError::type_mismatch(name, "a number", lhs),
+            (_, None) => RuntimeError::type_mismatch(name, "a number", rhs),
+        },
     }
 }
 
 pub fn less_than(_env: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
-    assert!(args.len() == 2);
+    RuntimeError::check_arity("<", Arity::Exactly(2), args)?;
     let lhs = &args[0];
     let rhs = &args[1];
     match (lhs, rhs) {
         (Value::Int(lhs_val), Value::Int(rhs_val)) => Ok(Value::Boolean(lhs_val < rhs_val)),
-        _ => RuntimeError::new(&format!(
-            "Expected both args to '<' to be integers. \
-                 Found {:?} and {:?}.",
-            lhs, rhs
-        )),
+        _ => match (as_float(lhs), as_float(rhs)) {
+            (Some(lhs_val), Some(rhs_val)) => Ok(Value::Boolean(lhs_val < rhs_val)),
+            (None, _) => RuntimeError::type_mismatch("<", "a number", lhs),
+            (_, None) => RuntimeError::type_mismatch("<", "a number", rhs),
+        },
     }
 }
 
-pub fn list(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> RuspResult {
-    let mut lst = Vec::new();
-    for arg in args {
-        lst.push(eval(env, ctx, arg)?);
-    }
-    Ok(Value::List(lst))
+pub fn list(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    Ok(Value::List(args.to_vec().into()))
 }
 
-pub fn let_impl(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> RuspResult {
+pub fn let_impl(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
     // Expect (let <Id> <Value> <body>)
-    assert!(args.len() == 3);
+    RuntimeError::check_arity("let", Arity::Exactly(3), args)?;
     let id_node = &args[0];
-    let bound_value = eval(env, ctx, &args[1])?;
-    let body_node = &args[2];
+    let body_node = args[2].clone();
 
-    if let ASTNode::Identifier { name } = id_node {
-        let new_env = env.extend(name, bound_value);
-        eval(&new_env, ctx, body_node)
+    if let ASTNode::Identifier { name, .. } = id_node {
+        let name = name.to_owned();
+        let env = env.clone();
+        Ok(Step::EvalThen(
+            env.clone(),
+            args[1].clone(),
+            Box::new(move |_, bound_value| {
+                Ok(Step::Eval(env.extend(&name, bound_value), body_node))
+            }),
+        ))
     } else {
-        RuntimeError::new(&format!(
+        RuntimeError::malformed(&format!(
             "Could not bind identifier in let expression because \
                      first argument was not an identifier. Found: {:?}",
             id_node
@@ -78,114 +111,547 @@ pub fn let_impl(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> RuspR
 }
 
 pub fn to_str(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
-    assert!(args.len() == 1);
-    Ok(Value::Str(args[0].runtime_to_str()?))
+    RuntimeError::check_arity("str", Arity::Exactly(1), args)?;
+    match args[0].runtime_to_str() {
+        Ok(s) => Ok(Value::Str(s)),
+        Err(_) => RuntimeError::type_mismatch("str", "a printable value", &args[0]),
+    }
 }
 
-pub fn write_impl(env: &Environment, ctx: &mut Context, args: &[Value]) -> RuspResult {
-    assert!(args.len() == 1);
-    let str_value = to_str(env, ctx, args)?;
-    if let Value::Str(v) = str_value {
-        ctx.stdout.write(v.as_bytes());
-        Ok(Value::Unit)
-    } else {
-        RuntimeError::new(&format!("Could not print value: {:?}", str_value))
+pub fn write_impl(_: &Environment, ctx: &mut Context, args: &[Value]) -> RuspResult {
+    RuntimeError::check_arity("write", Arity::Exactly(1), args)?;
+    match args[0].runtime_to_str() {
+        Ok(s) => {
+            ctx.stdout.write(s.as_bytes())?;
+            Ok(Value::Unit)
+        }
+        Err(_) => RuntimeError::type_mismatch("write", "a printable value", &args[0]),
     }
 }
 
-pub fn defun(env: &mut Environment, args: &[ASTNode]) -> RuspResult {
-    let name = if let ASTNode::Identifier { name } = &args[0] {
-        Ok(name)
-    } else {
-        Err(format!(
-            "Expected identifier as first argument to defun. Found {:?}",
-            args[0]
-        ))
-    }?;
+// Stops the program with an error made from the value passed.
+pub fn error(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    RuntimeError::check_arity("error", Arity::Exactly(1), args)?;
+    RuntimeError::new(ErrorKind::UserRaised {
+        value: args[0].clone(),
+    })
+}
+
+// Raises the value passed as an error, which try can catch. Errors which
+// were caught are raised again as they were.
+pub fn throw(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    raise_value("throw", args)
+}
+
+pub fn raise(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    raise_value("raise", args)
+}
+
+fn raise_value(name: &str, args: &[Value]) -> RuspResult {
+    RuntimeError::check_arity(name, Arity::Exactly(1), args)?;
+    match &args[0] {
+        Value::Error(error) => Err(RuntimeError::clone(error)),
+        value => RuntimeError::new(ErrorKind::UserRaised {
+            value: value.clone(),
+        }),
+    }
+}
+
+pub fn error_kind(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    let error = expect_error("error-kind", args)?;
+    Ok(Value::Symbol(error.kind.name().to_owned()))
+}
+
+pub fn error_message(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    let error = expect_error("error-message", args)?;
+    Ok(Value::Str(error.message()))
+}
+
+pub fn error_data(_: &Environment, _: &mut Context, args: &[Value]) -> RuspResult {
+    let error = expect_error("error-data", args)?;
+    Ok(error.kind.data())
+}
+
+fn expect_error<'a>(name: &str, args: &'a [Value]) -> Result<&'a RuntimeError, RuntimeError> {
+    RuntimeError::check_arity(name, Arity::Exactly(1), args)?;
+    match &args[0] {
+        Value::Error(error) => Ok(error),
+        other => RuntimeError::type_mismatch(name, "an error", other),
+    }
+}
+
+pub fn defun(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (defun <Id> (<Id>...) <body>...)
+    RuntimeError::check_arity("defun", Arity::AtLeast(2), args)?;
+    let name = defined_name("defun", &args[0])?;
     let ids = expect_id_list(&args[1])?;
-    let body = &args[1..];
-    let closure = Value::Closure(ClosureImpl::new_rc(&ids, body));
-    env.insert(name, closure);
-    Ok(Value::Unit)
+    let body = &args[2..];
+    // The closure finds itself through the shared globals, or the scope it's
+    // bound in, so it can recurse.
+    let closure = Value::Closure(ClosureImpl::new_rc(name, &ids, body, env));
+    bind_definition(env, &args[0], closure)?;
+    Ok(Step::Done(Value::Unit))
 }
 
-fn expect_id_list(node: &ASTNode) -> Result<Vec<String>, String> {
-    let mut ids: Vec<String> = Vec::new();
-    if let ASTNode::SExpr { children } = node {
-        for id in children {
-            if let ASTNode::Identifier { name } = id {
-                ids.push(name.to_owned());
+pub fn define(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (define <Id> <Value>)
+    RuntimeError::check_arity("define", Arity::Exactly(2), args)?;
+    defined_name("define", &args[0])?;
+    let name = args[0].clone();
+    let env = env.clone();
+    Ok(Step::EvalThen(
+        env.clone(),
+        args[1].clone(),
+        Box::new(move |_, value| {
+            bind_definition(&env, &name, value)?;
+            Ok(Step::Done(Value::Unit))
+        }),
+    ))
+}
+
+// The name a define or defun defines. The resolver turns it into a LocalRef
+// when the definition is local to a function body.
+pub fn defined_name<'a>(form: &str, node: &'a ASTNode) -> Result<&'a str, RuntimeError> {
+    match node {
+        ASTNode::Identifier { name, .. } => Ok(name),
+        ASTNode::LocalRef { name, .. } => Ok(name),
+        other => RuntimeError::malformed(&format!(
+            "Expected identifier as first argument to {}. Found {:?}",
+            form, other
+        )),
+    }
+}
+
+// Binds the name, checked by defined_name, to value.
+fn bind_definition(env: &Environment, name: &ASTNode, value: Value) -> Result<(), RuntimeError> {
+    match name {
+        ASTNode::LocalRef { depth, index, .. } => {
+            if env.set_local(*depth, *index, value) {
+                Ok(())
             } else {
-                return Err(format!(
-                    "Found expression in lambda arg list that \
-                             isn't an identifier: {:?}",
-                    node
-                ));
+                Err("Found a variable in an unknown scope.".to_owned().into())
             }
         }
+        ASTNode::Identifier { name, .. } => {
+            env.define(name, value);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn set(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (set! <Id> <Value>), where the resolver has usually worked out
+    // which binding <Id> is already.
+    RuntimeError::check_arity("set!", Arity::Exactly(2), args)?;
+    let target = args[0].clone();
+    let name = match &target {
+        ASTNode::LocalRef { name, .. } | ASTNode::GlobalRef { name, .. } => name.to_string(),
+        ASTNode::Identifier { name, .. } => name.to_owned(),
+        other => {
+            return RuntimeError::malformed(&format!(
+                "Expected identifier as first argument to set!. Found {:?}",
+                other
+            ))
+        }
+    };
+    let env = env.clone();
+    Ok(Step::EvalThen(
+        env.clone(),
+        args[1].clone(),
+        Box::new(move |_, value| {
+            let found = match target {
+                ASTNode::LocalRef { depth, index, .. } => env.set_local(depth, index, value),
+                ASTNode::GlobalRef { .. } => env.set_global(&name, value),
+                _ => env.set(&name, value),
+            };
+            expect_assigned(found, &name).map_err(|e| e.or_at(target.span()))?;
+            Ok(Step::Done(Value::Unit))
+        }),
+    ))
+}
+
+pub fn expect_id_list(node: &ASTNode) -> Result<Vec<String>, RuntimeError> {
+    let children = match node {
+        ASTNode::SExpr { children, .. } => children,
+        _ => {
+            return RuntimeError::malformed(&format!(
+                "Expected a list of identifiers as lambda arg list. Found {:?}",
+                node
+            ))
+        }
+    };
+    let mut ids: Vec<String> = Vec::new();
+    for id in children.iter() {
+        if let ASTNode::Identifier { name, .. } = id {
+            ids.push(name.to_owned());
+        } else {
+            return RuntimeError::malformed(&format!(
+                "Found expression in lambda arg list that \
+                         isn't an identifier: {:?}",
+                node
+            ));
+        }
     }
     Ok(ids)
 }
 
-pub fn lambda(_: &Environment, _: &mut Context, args: &[ASTNode]) -> RuspResult {
-    assert!(args.len() == 2);
+pub fn lambda(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    RuntimeError::check_arity("lambda", Arity::AtLeast(1), args)?;
     let ids = expect_id_list(&args[0])?;
-    Ok(Value::Closure(ClosureImpl::new_rc(&ids, args)))
+    let closure = ClosureImpl::new_rc("lambda", &ids, &args[1..], env);
+    Ok(Step::Done(Value::Closure(closure)))
 }
 
 pub struct ClosureImpl {
+    // What the closure was defined as with defun, or "lambda".
+    name: std::rc::Rc<str>,
     ids: Vec<String>,
+    // The names body defines. See internal_definitions.
+    locals: Vec<String>,
     body: Vec<ASTNode>,
+    // The environment the closure was created in. Its body is evaluated in
+    // this rather than the caller's environment.
+    env: Environment,
 }
 
 impl ClosureImpl {
-    pub fn new_rc(ids: &[String], body: &[ASTNode]) -> std::rc::Rc<ClosureImpl> {
+    pub fn new_rc(
+        name: &str,
+        ids: &[String],
+        body: &[ASTNode],
+        env: &Environment,
+    ) -> std::rc::Rc<ClosureImpl> {
         std::rc::Rc::new(ClosureImpl {
+            name: name.into(),
             ids: ids.to_owned(),
+            locals: internal_definitions(body),
             body: body.to_owned(),
+            env: env.clone(),
         })
     }
 }
 
 impl Callable for ClosureImpl {
-    fn invoke(&self, env: &Environment, ctx: &mut Context, args: &[Value]) -> RuspResult {
-        assert!(
-            self.ids.len() == args.len(),
-            "Invalid number of arguments passed to lambda expression.\n\
-             \tExpected: {}\n\tFound: {}",
-            self.ids.len(),
-            args.len()
-        );
-
-        // Create a new environment by binding all the ids to values.
-        let mut new_env = env.clone();
-        self.ids.iter().zip(args.iter()).for_each(|(id, value)| {
-            new_env.insert(id, value.clone());
-        });
-
-        // Evaluate each expression in body with the new environment applied.
-        self.body
-            .iter()
-            .map(|expr| eval(&new_env, ctx, expr))
-            .last()
-            .or_else(|| {
-                Some(RuntimeError::new(
-                    "Not enough arguments passed to callable.",
-                ))
-            })
-            .unwrap()
-    }
-}
-
-pub fn if_impl(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> RuspResult {
-    assert!(args.len() == 3);
-    if let Value::Boolean(condition) = eval(env, ctx, &args[0])? {
-        if condition {
-            Ok(eval(env, ctx, &args[1]).unwrap())
+    fn invoke(&self, ctx: &mut Context, args: &[Value]) -> StepResult {
+        RuntimeError::check_arity(&self.name, Arity::Exactly(self.ids.len()), args)?;
+
+        // Create a new environment by binding all the ids to values. The
+        // names the body defines are bound too, but can't be read until the
+        // body defines them.
+        let bindings = self.ids.iter().cloned().zip(args.iter().cloned());
+        let new_env = self.env.extend_defining(bindings.collect(), &self.locals);
+
+        if self.body.is_empty() {
+            return RuntimeError::malformed("Function body is empty.");
+        }
+        eval_body(ctx, new_env, &self.body)
+    }
+
+    fn name(&self) -> std::rc::Rc<str> {
+        self.name.clone()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+// Evaluates each expression in the non-empty body in turn. The last one is a
+// tail call, so it's left for the caller.
+fn eval_body(ctx: &mut Context, env: Environment, body: &[ASTNode]) -> StepResult {
+    let (last, init) = match body.split_last() {
+        Some((last, init)) => (last.clone(), init.to_vec()),
+        None => return Ok(Step::Done(Value::Unit)),
+    };
+    eval_each(ctx, env.clone(), init, move |_, _| {
+        Ok(Step::Eval(env, last))
+    })
+}
+
+pub fn if_impl(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    RuntimeError::check_arity("if", Arity::Exactly(3), args)?;
+    let (then_branch, else_branch) = (args[1].clone(), args[2].clone());
+    let env = env.clone();
+    Ok(Step::EvalThen(
+        env.clone(),
+        args[0].clone(),
+        Box::new(move |_, condition| match condition {
+            Value::Boolean(true) => Ok(Step::Eval(env, then_branch)),
+            Value::Boolean(false) => Ok(Step::Eval(env, else_branch)),
+            other => RuntimeError::type_mismatch("if", "a bool", &other),
+        }),
+    ))
+}
+
+pub fn quote(_: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (quote <expr>)
+    RuntimeError::check_arity("quote", Arity::Exactly(1), args)?;
+    Value::from_ast(&args[0]).map(Step::Done)
+}
+
+pub fn quasiquote(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (quasiquote <template>)
+    RuntimeError::check_arity("quasiquote", Arity::Exactly(1), args)?;
+    if form_argument(&args[0], "unquote-splicing").is_some() {
+        return RuntimeError::malformed("Found ,@ outside of a list in quasiquote.");
+    }
+    let template = args[0].clone();
+    let mut unquoted = Vec::new();
+    collect_unquoted(&template, 1, &mut unquoted);
+    eval_each(ctx, env.clone(), unquoted, move |_, values| {
+        expand_quasiquote(&template, 1, &mut values.into_iter()).map(Step::Done)
+    })
+}
+
+pub fn unquote(_: &Environment, _: &mut Context, _: &[ASTNode]) -> StepResult {
+    RuntimeError::malformed("Found unquote (,) outside of a quasiquote.")
+}
+
+pub fn unquote_splicing(_: &Environment, _: &mut Context, _: &[ASTNode]) -> StepResult {
+    RuntimeError::malformed("Found unquote-splicing (,@) outside of a quasiquote.")
+}
+
+// If node is (name <arg>), returns arg.
+pub fn form_argument<'a>(node: &'a ASTNode, name: &str) -> Option<&'a ASTNode> {
+    match node {
+        ASTNode::SExpr { children, .. } if children.len() == 2 => match &children[0] {
+            ASTNode::Identifier { name: head, .. } if head == name => Some(&children[1]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Finds the expressions in a quasiquote template which need evaluating, in
+// the order expand_quasiquote uses their values. depth is as for
+// expand_quasiquote.
+fn collect_unquoted(template: &ASTNode, depth: usize, unquoted: &mut Vec<ASTNode>) {
+    if let Some(inner) = form_argument(template, "unquote") {
+        if depth == 1 {
+            unquoted.push(inner.clone());
         } else {
-            Ok(eval(env, ctx, &args[2]).unwrap())
+            collect_unquoted(inner, depth - 1, unquoted);
+        }
+    } else if let Some(inner) = form_argument(template, "quasiquote") {
+        collect_unquoted(inner, depth + 1, unquoted);
+    } else if let ASTNode::SExpr { children, .. } = template {
+        for child in children.iter() {
+            match form_argument(child, "unquote-splicing") {
+                Some(inner) if depth == 1 => unquoted.push(inner.clone()),
+                Some(inner) => collect_unquoted(inner, depth - 1, unquoted),
+                None => collect_unquoted(child, depth, unquoted),
+            }
         }
+    }
+}
+
+// Builds the data described by a quasiquote template. depth is the number of
+// quasiquotes template is nested inside of. Only unquotes matching the
+// outermost quasiquote, at depth 1, are replaced by their values, which are
+// taken in turn from values. The rest are kept as data.
+fn expand_quasiquote(
+    template: &ASTNode,
+    depth: usize,
+    values: &mut dyn Iterator<Item = Value>,
+) -> RuspResult {
+    if let Some(inner) = form_argument(template, "unquote") {
+        return if depth == 1 {
+            match values.next() {
+                Some(value) => Ok(value),
+                None => RuntimeError::malformed("Found no value for ',' in quasiquote."),
+            }
+        } else {
+            Ok(Value::List(
+                vec![
+                    Value::Symbol("unquote".to_owned()),
+                    expand_quasiquote(inner, depth - 1, values)?,
+                ]
+                .into(),
+            ))
+        };
+    }
+    if let Some(inner) = form_argument(template, "quasiquote") {
+        return Ok(Value::List(
+            vec![
+                Value::Symbol("quasiquote".to_owned()),
+                expand_quasiquote(inner, depth + 1, values)?,
+            ]
+            .into(),
+        ));
+    }
+    if let ASTNode::SExpr { children, .. } = template {
+        let mut lst = Vec::new();
+        for child in children.iter() {
+            match form_argument(child, "unquote-splicing") {
+                Some(_) if depth == 1 => match values.next() {
+                    Some(Value::List(items)) => lst.extend(items.into_vec()),
+                    Some(other) => {
+                        return RuntimeError::type_mismatch("unquote-splicing", "a list", &other)
+                    }
+                    None => {
+                        return RuntimeError::malformed("Found no value for ',@' in quasiquote.")
+                    }
+                },
+                Some(inner) => lst.push(Value::List(
+                    vec![
+                        Value::Symbol("unquote-splicing".to_owned()),
+                        expand_quasiquote(inner, depth - 1, values)?,
+                    ]
+                    .into(),
+                )),
+                None => lst.push(expand_quasiquote(child, depth, values)?),
+            }
+        }
+        Ok(Value::List(lst.into()))
     } else {
-        RuntimeError::new("Could not evaluate value as bool.")
+        Value::from_ast(template)
     }
 }
+
+pub fn eval_impl(env: &Environment, _: &mut Context, args: &[ASTNode]) -> StepResult {
+    // Expect (eval <expr>) where expr evaluates to data representing code.
+    RuntimeError::check_arity("eval", Arity::Exactly(1), args)?;
+    let env = env.clone();
+    let span = args[0].span();
+    Ok(Step::EvalThen(
+        env.clone(),
+        args[0].clone(),
+        Box::new(move |ctx, code| {
+            let code = code_from_data(&code, &env, ctx, span)?;
+            Ok(Step::Eval(env, code))
+        }),
+    ))
+}
+
+// Returns the code the macro used by the data passed expands to, or the data
+// itself if it isn't the use of a macro.
+pub fn macroexpand_1(env: &Environment, ctx: &mut Context, args: &[Value]) -> RuspResult {
+    RuntimeError::check_arity("macroexpand-1", Arity::Exactly(1), args)?;
+    Ok(expand_once(env, ctx, &args[0])?.unwrap_or_else(|| args[0].clone()))
+}
+
+// Like macroexpand-1, but keeps expanding until the result isn't the use of
+// a macro.
+pub fn macroexpand(env: &Environment, ctx: &mut Context, args: &[Value]) -> RuspResult {
+    RuntimeError::check_arity("macroexpand", Arity::Exactly(1), args)?;
+    expand_fully(env, ctx, &args[0])
+}
+
+// The parts of (try <body>... (catch <Id> <handler>...) (finally <cleanup>...)).
+// Either clause may be left out.
+pub struct TryForm<'a> {
+    pub body: &'a [ASTNode],
+    pub catch: Option<(&'a str, &'a [ASTNode])>,
+    pub finally: Option<&'a [ASTNode]>,
+}
+
+impl<'a> TryForm<'a> {
+    pub fn parse(args: &'a [ASTNode]) -> Result<TryForm<'a>, RuntimeError> {
+        let body_length = args
+            .iter()
+            .position(|arg| try_clause(arg).is_some())
+            .unwrap_or(args.len());
+        let (body, clauses) = args.split_at(body_length);
+        if body.is_empty() {
+            return RuntimeError::malformed("Expected an expression before the clauses of try.");
+        }
+        let mut form = TryForm {
+            body,
+            catch: None,
+            finally: None,
+        };
+        for clause in clauses {
+            match try_clause(clause) {
+                Some(("catch", [ASTNode::Identifier { name, .. }, handler @ ..]))
+                    if !handler.is_empty() && form.catch.is_none() && form.finally.is_none() =>
+                {
+                    form.catch = Some((name, handler))
+                }
+                Some(("finally", cleanup)) if form.finally.is_none() => {
+                    form.finally = Some(cleanup)
+                }
+                _ => {
+                    return RuntimeError::malformed(
+                        "Expected at most (catch <Id> <expr>...) then (finally <expr>...) \
+                         after the body of try.",
+                    )
+                }
+            }
+        }
+        Ok(form)
+    }
+
+    // (unwind-protect <expr> <cleanup>...) is (try <expr> (finally <cleanup>...)).
+    pub fn parse_unwind_protect(args: &'a [ASTNode]) -> Result<TryForm<'a>, RuntimeError> {
+        RuntimeError::check_arity("unwind-protect", Arity::AtLeast(1), args)?;
+        Ok(TryForm {
+            body: &args[..1],
+            catch: None,
+            finally: Some(&args[1..]),
+        })
+    }
+}
+
+// If node is a (catch ...) or (finally ...) clause of a try, returns which,
+// along with the rest of the clause.
+pub fn try_clause(node: &ASTNode) -> Option<(&str, &[ASTNode])> {
+    match node {
+        ASTNode::SExpr { children, .. } => match children.first() {
+            Some(ASTNode::Identifier { name, .. }) if name == "catch" || name == "finally" => {
+                Some((name, &children[1..]))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn try_impl(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> StepResult {
+    protect(env, ctx, TryForm::parse(args)?)
+}
+
+pub fn unwind_protect(env: &Environment, ctx: &mut Context, args: &[ASTNode]) -> StepResult {
+    protect(env, ctx, TryForm::parse_unwind_protect(args)?)
+}
+
+// Evaluates the body of form, catching any error with its catch clause and
+// then running its finally clause however the body or handler finished.
+fn protect(env: &Environment, ctx: &mut Context, form: TryForm) -> StepResult {
+    let body = eval_body(ctx, env.clone(), form.body)?;
+    let catch = form
+        .catch
+        .map(|(name, handler)| (name.to_owned(), handler.to_vec()));
+    let cleanup = form.finally.map(<[ASTNode]>::to_vec);
+    let env = env.clone();
+    Ok(Step::Protect(
+        Box::new(body),
+        Box::new(move |ctx, result| match (result, catch) {
+            (Err(error), Some((name, handler))) => {
+                let handler_env = env.extend(&name, Value::Error(std::rc::Rc::new(error)));
+                let handled = eval_body(ctx, handler_env, &handler)?;
+                match cleanup {
+                    Some(cleanup) => Ok(Step::Protect(
+                        Box::new(handled),
+                        Box::new(move |ctx, result| clean_up(ctx, env, cleanup, result)),
+                    )),
+                    None => Ok(handled),
+                }
+            }
+            (result, _) => match cleanup {
+                Some(cleanup) => clean_up(ctx, env, cleanup, result),
+                None => result.map(Step::Done),
+            },
+        }),
+    ))
+}
+
+// Evaluates cleanup, then finishes as result did.
+fn clean_up(
+    ctx: &mut Context,
+    env: Environment,
+    cleanup: Vec<ASTNode>,
+    result: RuspResult,
+) -> StepResult {
+    eval_each(ctx, env, cleanup, move |_, _| result.map(Step::Done))
+}
@@ -1,41 +1,199 @@
+use super::error::{ErrorKind, RuntimeError};
 use super::value::Value;
 use crate::eval::io::IOStream;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+
+// The default for Context::max_depth. Each frame takes up around a hundred
+// bytes, so this is enough for deep recursion without using up all memory.
+pub const DEFAULT_MAX_DEPTH: usize = 1_000_000;
 
 pub struct Context {
     pub stdout: IOStream,
+    // How deep evaluation may nest before failing with a stack depth error.
+    pub max_depth: usize,
+    // How many identifiers syntax-rules templates have renamed, so every
+    // renamed identifier gets a fresh name.
+    pub renamed: usize,
 }
 
 impl Context {
     pub fn new(stdout: IOStream) -> Context {
-        Context { stdout }
+        Context {
+            stdout,
+            max_depth: DEFAULT_MAX_DEPTH,
+            renamed: 0,
+        }
     }
 }
 
+// The bindings visible at some point in a program. Local bindings, from let
+// and function arguments, are kept in a chain of scopes, innermost first.
+// Adding a scope shares the rest of the chain rather than copying it, so
+// cloning an environment or binding a variable costs the same however many
+// bindings are already visible. Since scopes are shared, a binding assigned
+// with set! changes for every closure which captured it. Global bindings,
+// from builtins, defun and define, are shared by every environment made from
+// the same Environment::new, so a closure sees globals defined after it was
+// created, including itself.
 #[derive(Clone)]
 pub struct Environment {
-    value_map: HashMap<String, Value>,
+    scope: Option<Rc<Scope>>,
+    globals: Rc<RefCell<HashMap<String, Value>>>,
+}
+
+// The variables bound by one let or function call. A variable is None until
+// the definition which binds it has run. See extend_defining.
+struct Scope {
+    bindings: Vec<(String, RefCell<Option<Value>>)>,
+    parent: Option<Rc<Scope>>,
 }
 
 impl Environment {
     pub fn new() -> Environment {
         Environment {
-            value_map: HashMap::new(),
+            scope: None,
+            globals: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    // Looks up name, failing if it's bound by a definition which hasn't run
+    // yet.
+    pub fn get(&self, name: &str) -> Result<Option<Value>, RuntimeError> {
+        let mut scope = self.scope.as_deref();
+        while let Some(Scope { bindings, parent }) = scope {
+            // Search backwards so later bindings of a name shadow earlier ones.
+            if let Some((id, value)) = bindings.iter().rev().find(|(id, _)| id == name) {
+                return defined(id, value).map(Some);
+            }
+            scope = parent.as_deref();
+        }
+        Ok(self.globals.borrow().get(name).cloned())
+    }
+
+    // Looks up the value at index in the scope depth scopes out from the
+    // innermost one. See ASTNode::LocalRef.
+    pub fn get_local(&self, depth: usize, index: usize) -> Result<Option<Value>, RuntimeError> {
+        match self.local(depth, index) {
+            Some((id, value)) => defined(id, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn local(&self, depth: usize, index: usize) -> Option<&(String, RefCell<Option<Value>>)> {
+        let mut scope = self.scope.as_deref()?;
+        for _ in 0..depth {
+            scope = scope.parent.as_deref()?;
+        }
+        scope.bindings.get(index)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name).cloned()
+    }
+
+    // Whether any global's value satisfies predicate.
+    pub fn any_global(&self, predicate: impl Fn(&Value) -> bool) -> bool {
+        self.globals.borrow().values().any(predicate)
+    }
+
+    // The names bound in each local scope, outermost first.
+    pub fn scope_names(&self) -> Vec<Vec<String>> {
+        let mut names = Vec::new();
+        let mut scope = self.scope.as_deref();
+        while let Some(Scope { bindings, parent }) = scope {
+            names.push(bindings.iter().map(|(name, _)| name.clone()).collect());
+            scope = parent.as_deref();
         }
+        names.reverse();
+        names
     }
 
-    #[allow(clippy::borrowed_box)]
-    pub fn get(&self, name: &str) -> Option<&Value> {
-        self.value_map.get(name)
+    // Replaces the value at index in the scope depth scopes out from the
+    // innermost one. Returns whether there was one.
+    pub fn set_local(&self, depth: usize, index: usize, value: Value) -> bool {
+        match self.local(depth, index) {
+            Some((_, binding)) => {
+                binding.replace(Some(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Replaces the value of the global name. Returns whether it was bound.
+    pub fn set_global(&self, name: &str, value: Value) -> bool {
+        match self.globals.borrow_mut().get_mut(name) {
+            Some(binding) => {
+                *binding = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Replaces the value of the innermost binding of name, local or global.
+    // Returns whether it was bound.
+    pub fn set(&self, name: &str, value: Value) -> bool {
+        let mut scope = self.scope.as_deref();
+        while let Some(Scope { bindings, parent }) = scope {
+            if let Some((_, binding)) = bindings.iter().rev().find(|(id, _)| id == name) {
+                binding.replace(Some(value));
+                return true;
+            }
+            scope = parent.as_deref();
+        }
+        self.set_global(name, value)
     }
 
-    pub fn insert(&mut self, name: &str, value: Value) {
-        self.value_map.insert(String::from(name), value);
+    // Binds name globally, making it visible to every environment sharing
+    // this one's globals.
+    pub fn define(&self, name: &str, value: Value) {
+        self.globals.borrow_mut().insert(String::from(name), value);
     }
 
     pub fn extend(&self, name: &str, v: Value) -> Environment {
-        let mut new_env = self.clone();
-        new_env.insert(name, v);
-        new_env
+        self.extend_all(vec![(String::from(name), v)])
+    }
+
+    // Returns this environment without its innermost scope.
+    pub fn outer(&self) -> Environment {
+        Environment {
+            scope: self.scope.as_ref().and_then(|scope| scope.parent.clone()),
+            globals: self.globals.clone(),
+        }
+    }
+
+    // Returns a new environment with a scope holding bindings inside this one.
+    pub fn extend_all(&self, bindings: Vec<(String, Value)>) -> Environment {
+        self.extend_defining(bindings, &[])
+    }
+
+    // Like extend_all, but the scope also binds names, which are an error to
+    // read until a definition assigns them.
+    pub fn extend_defining(&self, bindings: Vec<(String, Value)>, names: &[String]) -> Environment {
+        let undefined = names.iter().map(|name| (name.clone(), RefCell::new(None)));
+        Environment {
+            scope: Some(Rc::new(Scope {
+                bindings: bindings
+                    .into_iter()
+                    .map(|(name, value)| (name, RefCell::new(Some(value))))
+                    .chain(undefined)
+                    .collect(),
+                parent: self.scope.clone(),
+            })),
+            globals: self.globals.clone(),
+        }
+    }
+}
+
+// The value of the variable name, or an error if it hasn't been defined yet.
+fn defined(name: &str, value: &RefCell<Option<Value>>) -> Result<Value, RuntimeError> {
+    match &*value.borrow() {
+        Some(value) => Ok(value.clone()),
+        None => RuntimeError::new(ErrorKind::UsedBeforeDefinition {
+            name: name.to_owned(),
+        }),
     }
 }
@@ -1,22 +1,296 @@
-#[derive(Debug)]
+use super::value::Value;
+use crate::span::Span;
+use std::fmt;
+use std::rc::Rc;
+
+// The most calls recorded in a backtrace. Deep recursion can leave far more
+// calls in progress than are worth reporting.
+const BACKTRACE_LIMIT: usize = 16;
+
+#[derive(Debug, Clone)]
 pub struct RuntimeError {
-    pub message: String,
+    pub kind: ErrorKind,
+    // Where in the source the error was raised. Builtins don't know their
+    // call site, so this is filled in by eval as the error propagates.
+    // This and backtrace are boxed, as errors are passed around far more
+    // often than they're shown.
+    pub span: Option<Box<Span>>,
+    pub backtrace: Box<Backtrace>,
+}
+
+// The function calls in progress when an error was raised.
+#[derive(Debug, Clone, Default)]
+pub struct Backtrace {
+    // Innermost first, up to BACKTRACE_LIMIT of them.
+    pub calls: Vec<TraceFrame>,
+    // How many calls in progress didn't fit in calls.
+    pub omitted: usize,
+}
+
+// A call to a function which hadn't returned when an error was raised.
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub name: Rc<str>,
+    pub call_site: Span,
+}
+
+// What went wrong.
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    // A function or special form was passed the wrong number of arguments.
+    ArityMismatch {
+        name: String,
+        expected: Arity,
+        found: usize,
+    },
+    // An argument, or a value a special form relies on, had the wrong type.
+    // expected describes what was wanted, e.g. "a number".
+    TypeMismatch {
+        name: String,
+        expected: &'static str,
+        found: Value,
+    },
+    UnboundIdentifier {
+        name: String,
+    },
+    // A name bound by a definition in a function body was read before the
+    // definition ran.
+    UsedBeforeDefinition {
+        name: String,
+    },
+    // set! was used on a name which isn't bound.
+    UnboundAssignment {
+        name: String,
+    },
+    // Something other than a function was called.
+    NotCallable {
+        found: Value,
+    },
+    // Integer arithmetic went out of range.
+    Overflow {
+        name: String,
+        lhs: i64,
+        rhs: i64,
+    },
+    // Evaluation nested more deeply than Context::max_depth allows.
+    StackOverflow {
+        max_depth: usize,
+    },
+    // Raised by the program itself with error or throw.
+    UserRaised {
+        value: Value,
+    },
+    // Reading input or writing output failed.
+    IO {
+        message: String,
+    },
+    // A special form was written wrongly, e.g. (let 1 2 3).
+    Malformed {
+        message: String,
+    },
+    Other {
+        message: String,
+    },
+}
+
+// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Arity::Exactly(n) => count == *n,
+            Arity::AtLeast(n) => count >= *n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (prefix, n) = match self {
+            Arity::Exactly(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        let plural = if *n == 1 { "" } else { "s" };
+        write!(f, "{}{} argument{}", prefix, n, plural)
+    }
+}
+
+impl ErrorKind {
+    // Names the kind of error for programs which catch it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::ArityMismatch { .. } => "arity-mismatch",
+            ErrorKind::TypeMismatch { .. } => "type-mismatch",
+            ErrorKind::UnboundIdentifier { .. } => "unbound-identifier",
+            ErrorKind::UsedBeforeDefinition { .. } => "used-before-definition",
+            ErrorKind::UnboundAssignment { .. } => "unbound-assignment",
+            ErrorKind::NotCallable { .. } => "not-callable",
+            ErrorKind::Overflow { .. } => "overflow",
+            ErrorKind::StackOverflow { .. } => "stack-overflow",
+            ErrorKind::UserRaised { .. } => "user",
+            ErrorKind::IO { .. } => "io",
+            ErrorKind::Malformed { .. } => "malformed",
+            ErrorKind::Other { .. } => "other",
+        }
+    }
+
+    // The value the error is about, for programs which catch it.
+    pub fn data(&self) -> Value {
+        match self {
+            ErrorKind::ArityMismatch { found, .. } => Value::Int(*found as i64),
+            ErrorKind::TypeMismatch { found, .. } | ErrorKind::NotCallable { found } => {
+                found.clone()
+            }
+            ErrorKind::UnboundIdentifier { name }
+            | ErrorKind::UsedBeforeDefinition { name }
+            | ErrorKind::UnboundAssignment { name } => Value::Symbol(name.to_owned()),
+            ErrorKind::Overflow { lhs, rhs, .. } => {
+                Value::List(vec![Value::Int(*lhs), Value::Int(*rhs)].into())
+            }
+            ErrorKind::StackOverflow { max_depth } => Value::Int(*max_depth as i64),
+            ErrorKind::UserRaised { value } => value.clone(),
+            ErrorKind::IO { .. } | ErrorKind::Malformed { .. } | ErrorKind::Other { .. } => {
+                Value::Unit
+            }
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::ArityMismatch {
+                name,
+                expected,
+                found,
+            } => write!(f, "Expected {} to '{}'. Found {}.", expected, name, found),
+            ErrorKind::TypeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected {} for '{}'. Found {}.",
+                expected,
+                name,
+                found.describe()
+            ),
+            ErrorKind::UnboundIdentifier { name } => {
+                write!(f, "Failed to find identifier {:?} in environment.", name)
+            }
+            ErrorKind::UsedBeforeDefinition { name } => {
+                write!(f, "{:?} used before its definition.", name)
+            }
+            ErrorKind::UnboundAssignment { name } => write!(
+                f,
+                "Cannot set! {:?}, which isn't bound. Use define to create it.",
+                name
+            ),
+            ErrorKind::NotCallable { found } => {
+                write!(
+                    f,
+                    "Expected a function to call. Found {}.",
+                    found.describe()
+                )
+            }
+            ErrorKind::Overflow { name, lhs, rhs } => {
+                write!(f, "Integer overflow in '{}' of {} and {}.", name, lhs, rhs)
+            }
+            ErrorKind::StackOverflow { max_depth } => write!(
+                f,
+                "Stack depth exceeded. Evaluation nested more than {} frames deep.",
+                max_depth
+            ),
+            ErrorKind::UserRaised { value } => match value.runtime_to_str() {
+                Ok(s) => write!(f, "{}", s),
+                Err(_) => write!(f, "{}", value.describe()),
+            },
+            ErrorKind::IO { message } => write!(f, "{}", message),
+            ErrorKind::Malformed { message } | ErrorKind::Other { message } => {
+                write!(f, "{}", message)
+            }
+        }
+    }
 }
 
 impl RuntimeError {
-    pub fn new_err(message: &str) -> RuntimeError {
+    pub fn new_err(kind: ErrorKind) -> RuntimeError {
         RuntimeError {
+            kind,
+            span: None,
+            backtrace: Box::default(),
+        }
+    }
+
+    pub fn new<T>(kind: ErrorKind) -> Result<T, RuntimeError> {
+        Err(RuntimeError::new_err(kind))
+    }
+
+    // Fails because a special form was written wrongly, as described by
+    // message.
+    pub fn malformed<T>(message: &str) -> Result<T, RuntimeError> {
+        RuntimeError::new(ErrorKind::Malformed {
             message: message.to_owned(),
+        })
+    }
+
+    // Fails with ArityMismatch unless expected accepts the number of args
+    // passed to name.
+    pub fn check_arity<T>(name: &str, expected: Arity, args: &[T]) -> Result<(), RuntimeError> {
+        if expected.accepts(args.len()) {
+            return Ok(());
         }
+        RuntimeError::new(ErrorKind::ArityMismatch {
+            name: name.to_owned(),
+            expected,
+            found: args.len(),
+        })
+    }
+
+    pub fn type_mismatch<T>(
+        name: &str,
+        expected: &'static str,
+        found: &Value,
+    ) -> Result<T, RuntimeError> {
+        RuntimeError::new(ErrorKind::TypeMismatch {
+            name: name.to_owned(),
+            expected,
+            found: found.clone(),
+        })
     }
 
-    pub fn new<T>(message: &str) -> Result<T, RuntimeError> {
-        Err(RuntimeError::new_err(message))
+    pub fn message(&self) -> String {
+        self.kind.to_string()
+    }
+
+    // Records span as the location of this error unless a more precise one
+    // has already been recorded.
+    pub fn or_at(mut self, span: Span) -> RuntimeError {
+        self.span.get_or_insert_with(|| Box::new(span));
+        self
+    }
+
+    // Adds calls, innermost first, to the backtrace. They must be further out
+    // than any calls already recorded.
+    pub fn with_backtrace(mut self, calls: impl Iterator<Item = TraceFrame>) -> RuntimeError {
+        for call in calls {
+            if self.backtrace.calls.len() < BACKTRACE_LIMIT {
+                self.backtrace.calls.push(call);
+            } else {
+                self.backtrace.omitted += 1;
+            }
+        }
+        self
     }
 }
 
 impl From<String> for RuntimeError {
-    fn from(s: String) -> Self {
-        RuntimeError::new_err(&s)
+    fn from(message: String) -> Self {
+        RuntimeError::new_err(ErrorKind::Other { message })
     }
 }
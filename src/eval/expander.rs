@@ -0,0 +1,478 @@
+use super::builtins::{expect_id_list, form_argument, try_clause, ClosureImpl};
+use super::environment::{Context, Environment};
+use super::error::{Arity, RuntimeError};
+use super::resolver::{resolve, with_argument};
+use super::scope::{internal_definitions, Binding, Scopes};
+use super::syntax_rules::{is_renamed, original_name, SyntaxRules};
+use super::value::{Callable, Value};
+use super::{run_step, RuspResult};
+use crate::parser::ASTNode;
+use crate::span::Span;
+use std::rc::Rc;
+
+// How many times the code a macro returns may itself turn out to be a use of
+// a macro, so a macro expanding to itself fails rather than hanging.
+const EXPANSION_LIMIT: usize = 1000;
+
+// How deeply macro uses may be nested inside the code other macros return.
+// Expanding these recurses, so this is kept well short of overflowing.
+const NESTING_LIMIT: usize = 200;
+
+// Replaces every use of a macro in ast, which hasn't been resolved yet, with
+// the code the macro returns for it. A top level
+//
+//   (defmacro <Id> (<Id>...) <body>...)
+//
+// defines a macro in env as soon as it's reached, so the forms after it can
+// use it, and is replaced by its name quoted. A macro is called with the
+// forms it's used on as data and must return data representing code. A top
+// level define-syntax defines a macro in the same way. See SyntaxRules.
+//
+// Identifiers renamed by a syntax-rules template which nothing in the
+// expansion binds are given back their original names, unless a local
+// binding would capture them. Those are left for the resolver.
+//
+// Macros run while expanding, before any of ast has been evaluated, so their
+// bodies can only rely on functions defined by input which has already run.
+pub fn expand(
+    ast: &ASTNode,
+    env: &Environment,
+    ctx: &mut Context,
+) -> Result<ASTNode, RuntimeError> {
+    let mut expander = Expander {
+        env,
+        ctx,
+        scopes: Scopes::new(env),
+        expansions: 0,
+    };
+    expander.expand(ast, true)
+}
+
+// If form is data representing a use of a macro, returns the code the macro
+// returns for it, as data. Used by macroexpand.
+pub fn expand_once(
+    env: &Environment,
+    ctx: &mut Context,
+    form: &Value,
+) -> Result<Option<Value>, RuntimeError> {
+    let (name, args) = match form {
+        Value::List(items) => match items.split_first() {
+            Some((Value::Symbol(name), args)) => (name, args),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    match env.get_global(original_name(name)) {
+        Some(Value::Macro(m)) => call_macro(ctx, &m, args.to_vec()).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// Like expand_once, but expands the result again for as long as it's a use
+// of a macro.
+pub fn expand_fully(env: &Environment, ctx: &mut Context, form: &Value) -> RuspResult {
+    let mut form = form.clone();
+    for _ in 0..EXPANSION_LIMIT {
+        match expand_once(env, ctx, &form)? {
+            Some(expanded) => form = expanded,
+            None => return Ok(form),
+        }
+    }
+    too_many_expansions(EXPANSION_LIMIT)
+}
+
+fn call_macro(ctx: &mut Context, m: &Rc<dyn Callable>, args: Vec<Value>) -> RuspResult {
+    let step = m.invoke(ctx, &args)?;
+    run_step(ctx, step)
+}
+
+fn too_many_expansions<T>(limit: usize) -> Result<T, RuntimeError> {
+    Err(format!("Macro expansion went more than {} levels deep.", limit).into())
+}
+
+struct Expander<'a> {
+    env: &'a Environment,
+    ctx: &'a mut Context,
+    // Uses of local names aren't uses of a macro with the same name.
+    scopes: Scopes<'a>,
+    // The number of macro uses being expanded which led to the current one.
+    // See NESTING_LIMIT.
+    expansions: usize,
+}
+
+impl<'a> Expander<'a> {
+    fn expand(&mut self, node: &ASTNode, top_level: bool) -> Result<ASTNode, RuntimeError> {
+        match node {
+            ASTNode::Program { statements, span } => {
+                let mut expanded = Vec::new();
+                for statement in statements {
+                    expanded.push(self.expand(statement, true)?);
+                }
+                Ok(ASTNode::Program {
+                    statements: expanded,
+                    span: *span,
+                })
+            }
+            ASTNode::SExpr { children, span } if !children.is_empty() => {
+                self.expand_form(children, *span, top_level)
+            }
+            ASTNode::Identifier { name, span } if is_renamed(name) => {
+                Ok(self.unrename(name, *span))
+            }
+            _ => Ok(node.clone()),
+        }
+    }
+
+    fn expand_all(&mut self, nodes: &[ASTNode]) -> Result<Vec<ASTNode>, RuntimeError> {
+        nodes.iter().map(|node| self.expand(node, false)).collect()
+    }
+
+    fn expand_in_scope(
+        &mut self,
+        names: Vec<String>,
+        nodes: &[ASTNode],
+    ) -> Result<Vec<ASTNode>, RuntimeError> {
+        self.scopes.push(names);
+        let expanded = self.expand_all(nodes);
+        self.scopes.pop();
+        expanded
+    }
+
+    // Gives the renamed identifier name its original name if nothing binds
+    // it and no local binding of the original name would capture it.
+    fn unrename(&self, name: &str, span: Span) -> ASTNode {
+        let original = original_name(name);
+        if self.scopes.is_local(name) || self.scopes.is_local(original) {
+            identifier(name, span)
+        } else {
+            identifier(original, span)
+        }
+    }
+
+    // Expands the non-empty s-expression children. Like the resolver, this
+    // leaves the names special forms bind alone and doesn't look inside
+    // quoted data.
+    fn expand_form(
+        &mut self,
+        children: &[ASTNode],
+        span: Span,
+        top_level: bool,
+    ) -> Result<ASTNode, RuntimeError> {
+        let head = &self.expand(&children[0], false)?;
+        let args = &children[1..];
+        let global = self.scopes.global_name(head);
+        if let Some(definer @ ("defmacro" | "define-syntax")) = global {
+            if !top_level {
+                return RuntimeError::malformed(&format!(
+                    "{} can only be used at the top level.",
+                    definer
+                ))
+                .map_err(|e| e.or_at(span));
+            }
+            return match definer {
+                "defmacro" => self.define_macro(args, span),
+                _ => self.define_syntax(args, span),
+            };
+        }
+        if let Some(Value::Macro(m)) = global.and_then(|name| self.env.get_global(name)) {
+            return self.expand_use(&m, args, span, top_level);
+        }
+        let mut expanded = vec![head.clone()];
+        let form = self.scopes.special_form(head);
+        if let Some(binding) = form.and_then(|form| Binding::of(form, args)) {
+            expanded.extend(self.expand_binding(binding)?);
+            return Ok(ASTNode::SExpr {
+                children: expanded.into(),
+                span,
+            });
+        }
+        match (form, args) {
+            (Some("quote" | "unquote" | "unquote-splicing"), _) => {
+                expanded.extend(args.iter().map(strip_renames))
+            }
+            (Some("quasiquote"), [template]) => expanded.push(self.expand_template(template, 1)?),
+            (Some("try"), _) => {
+                for arg in args {
+                    let arg = self.expand_try_part(arg)?;
+                    expanded.push(arg);
+                }
+            }
+            _ => expanded.extend(self.expand_all(args)?),
+        }
+        Ok(ASTNode::SExpr {
+            children: expanded.into(),
+            span,
+        })
+    }
+
+    // Expands the arguments of a form binding names.
+    fn expand_binding(&mut self, binding: Binding) -> Result<Vec<ASTNode>, RuntimeError> {
+        let mut expanded = Vec::new();
+        match binding {
+            Binding::Let {
+                id,
+                name,
+                value,
+                body,
+            } => {
+                expanded.push(id.clone());
+                expanded.push(self.expand(value, false)?);
+                expanded.extend(
+                    self.expand_in_scope(vec![name.to_owned()], std::slice::from_ref(body))?,
+                );
+            }
+            Binding::Function {
+                name,
+                params,
+                names,
+                body,
+            } => {
+                // As with define, a name from a template keeps its rename
+                // only where a local binding would otherwise capture it.
+                if let Some(name) = name {
+                    expanded.push(self.expand(name, false)?);
+                }
+                expanded.push(params.clone());
+                expanded.extend(self.expand_in_scope(names, body)?);
+            }
+            Binding::Catch { id, name, handler } => {
+                expanded.push(id.clone());
+                expanded.extend(self.expand_in_scope(vec![name.to_owned()], handler)?);
+            }
+        }
+        Ok(expanded)
+    }
+
+    // Defines the macro (defmacro <args>...) and returns what the form is
+    // replaced by.
+    fn define_macro(&mut self, args: &[ASTNode], span: Span) -> Result<ASTNode, RuntimeError> {
+        RuntimeError::check_arity("defmacro", Arity::AtLeast(2), args)
+            .map_err(|e| e.or_at(span))?;
+        let name = match &args[0] {
+            ASTNode::Identifier { name, .. } => name,
+            other => {
+                return RuntimeError::malformed(&format!(
+                    "Expected identifier as first argument to defmacro. Found {:?}",
+                    other
+                ))
+                .map_err(|e| e.or_at(span))
+            }
+        };
+        let ids = expect_id_list(&args[1]).map_err(|e| e.or_at(span))?;
+        let mut names = ids.clone();
+        names.extend(internal_definitions(&args[2..]));
+        let body = self.expand_in_scope(names, &args[2..])?;
+
+        // The body is resolved as a lambda's would be, then closed over env.
+        let mut lambda = vec![identifier("lambda", span), args[1].clone()];
+        lambda.extend(body);
+        let lambda = ASTNode::SExpr {
+            children: lambda.into(),
+            span,
+        };
+        let body = match resolve(&lambda, self.env)? {
+            ASTNode::SExpr { children, .. } => children[2..].to_vec(),
+            _ => Vec::new(),
+        };
+        let m = ClosureImpl::new_rc(name, &ids, &body, self.env);
+        self.env.define(name, Value::Macro(m));
+        Ok(ASTNode::SExpr {
+            children: vec![identifier("quote", span), identifier(name, span)].into(),
+            span,
+        })
+    }
+
+    // Defines the macro (define-syntax <args>...) and returns what the form
+    // is replaced by.
+    fn define_syntax(&mut self, args: &[ASTNode], span: Span) -> Result<ASTNode, RuntimeError> {
+        RuntimeError::check_arity("define-syntax", Arity::Exactly(2), args)
+            .map_err(|e| e.or_at(span))?;
+        let name = match &args[0] {
+            ASTNode::Identifier { name, .. } => original_name(name),
+            other => {
+                return RuntimeError::malformed(&format!(
+                    "Expected identifier as first argument to define-syntax. Found {:?}",
+                    other
+                ))
+                .map_err(|e| e.or_at(span))
+            }
+        };
+        let spec = Value::from_ast(&args[1])?;
+        let rules = SyntaxRules::new(name, &spec).map_err(|e| e.or_at(span))?;
+        self.env.define(name, Value::Macro(Rc::new(rules)));
+        Ok(ASTNode::SExpr {
+            children: vec![identifier("quote", span), identifier(name, span)].into(),
+            span,
+        })
+    }
+
+    // Replaces a use of the macro m on args with the code it returns. That
+    // code is expanded in turn, and so on until it's no longer a macro use.
+    fn expand_use(
+        &mut self,
+        m: &Rc<dyn Callable>,
+        args: &[ASTNode],
+        span: Span,
+        top_level: bool,
+    ) -> Result<ASTNode, RuntimeError> {
+        if self.expansions >= NESTING_LIMIT {
+            return too_many_expansions(NESTING_LIMIT).map_err(|e: RuntimeError| e.or_at(span));
+        }
+        let mut m = m.clone();
+        let mut args = args.iter().map(Value::from_ast).collect::<Result<_, _>>()?;
+        let mut uses = 0;
+        let code = loop {
+            let code = call_macro(self.ctx, &m, args).map_err(|e| e.or_at(span))?;
+            uses += 1;
+            match self.macro_use(&code) {
+                Some((next, next_args)) => {
+                    if uses >= EXPANSION_LIMIT {
+                        return too_many_expansions(EXPANSION_LIMIT)
+                            .map_err(|e: RuntimeError| e.or_at(span));
+                    }
+                    m = next;
+                    args = next_args;
+                }
+                _ => break code,
+            }
+        };
+        let code = match code.to_ast(span) {
+            Ok(code) => code,
+            Err(_) => {
+                return RuntimeError::type_mismatch(&m.name(), "data representing code", &code)
+                    .map_err(|e| e.or_at(span))
+            }
+        };
+        self.expansions += 1;
+        let expanded = self.expand(&code, top_level);
+        self.expansions -= 1;
+        expanded
+    }
+
+    // If code is data representing a use of a macro, returns the macro and
+    // the forms it's used on.
+    fn macro_use(&self, code: &Value) -> Option<(Rc<dyn Callable>, Vec<Value>)> {
+        match code {
+            Value::List(items) => match items.split_first() {
+                Some((Value::Symbol(name), args)) if !self.scopes.is_local(name) => {
+                    match self.env.get_global(original_name(name)) {
+                        Some(Value::Macro(m)) => Some((m, args.to_vec())),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Expands an argument to try. The name in a catch clause is bound for the
+    // rest of the clause.
+    fn expand_try_part(&mut self, arg: &ASTNode) -> Result<ASTNode, RuntimeError> {
+        let arg = &with_keyword_head(arg, &["catch", "finally"]);
+        let (head, (clause, rest)) = match (arg, try_clause(arg)) {
+            (ASTNode::SExpr { children, .. }, Some(clause)) => (children[0].clone(), clause),
+            _ => return self.expand(arg, false),
+        };
+        let mut expanded = vec![head];
+        match Binding::of(clause, rest) {
+            Some(binding) => expanded.extend(self.expand_binding(binding)?),
+            None => expanded.extend(self.expand_all(rest)?),
+        }
+        Ok(ASTNode::SExpr {
+            children: expanded.into(),
+            span: arg.span(),
+        })
+    }
+
+    // Expands the code in a quasiquote template which will be evaluated,
+    // leaving the rest as data. Mirrors expand_quasiquote.
+    fn expand_template(
+        &mut self,
+        template: &ASTNode,
+        depth: usize,
+    ) -> Result<ASTNode, RuntimeError> {
+        let template = &with_keyword_head(template, QUASIQUOTE_KEYWORDS);
+        if let Some(inner) = form_argument(template, "unquote") {
+            let inner = if depth == 1 {
+                self.expand(inner, false)?
+            } else {
+                self.expand_template(inner, depth - 1)?
+            };
+            return Ok(with_argument(template, inner));
+        }
+        if let Some(inner) = form_argument(template, "quasiquote") {
+            let inner = self.expand_template(inner, depth + 1)?;
+            return Ok(with_argument(template, inner));
+        }
+        match template {
+            ASTNode::SExpr { children, span } => {
+                let mut expanded = Vec::new();
+                for child in children.iter() {
+                    let child = &with_keyword_head(child, QUASIQUOTE_KEYWORDS);
+                    let child = match form_argument(child, "unquote-splicing") {
+                        Some(inner) if depth == 1 => {
+                            with_argument(child, self.expand(inner, false)?)
+                        }
+                        Some(inner) => {
+                            let inner = self.expand_template(inner, depth - 1)?;
+                            with_argument(child, inner)
+                        }
+                        None => self.expand_template(child, depth)?,
+                    };
+                    expanded.push(child);
+                }
+                Ok(ASTNode::SExpr {
+                    children: expanded.into(),
+                    span: *span,
+                })
+            }
+            _ => Ok(strip_renames(template)),
+        }
+    }
+}
+
+const QUASIQUOTE_KEYWORDS: &[&str] = &["quasiquote", "unquote", "unquote-splicing"];
+
+// Keywords like catch are recognised by name alone, so if node is a form
+// whose head is one of keywords renamed by a syntax-rules template, this
+// gives it back its original name.
+fn with_keyword_head(node: &ASTNode, keywords: &[&str]) -> ASTNode {
+    match node {
+        ASTNode::SExpr { children, span } => match children.first() {
+            Some(ASTNode::Identifier {
+                name,
+                span: head_span,
+            }) if is_renamed(name) && keywords.contains(&original_name(name)) => {
+                let mut children = children.to_vec();
+                children[0] = identifier(original_name(name), *head_span);
+                ASTNode::SExpr {
+                    children: children.into(),
+                    span: *span,
+                }
+            }
+            _ => node.clone(),
+        },
+        _ => node.clone(),
+    }
+}
+
+// Gives every renamed identifier in node its original name. Used for code
+// which is quoted rather than evaluated.
+fn strip_renames(node: &ASTNode) -> ASTNode {
+    match node {
+        ASTNode::Identifier { name, span } => identifier(original_name(name), *span),
+        ASTNode::SExpr { children, span } => ASTNode::SExpr {
+            children: children.iter().map(strip_renames).collect(),
+            span: *span,
+        },
+        _ => node.clone(),
+    }
+}
+
+fn identifier(name: &str, span: Span) -> ASTNode {
+    ASTNode::Identifier {
+        name: name.to_owned(),
+        span,
+    }
+}
@@ -1,19 +1,27 @@
+use super::error::{ErrorKind, RuntimeError};
 use std::io::Write;
 
 pub enum IOStream {
+    // Only used when capturing output instead of printing it.
     InMemoryBuffer(Vec<u8>),
     Stdout(std::io::Stdout),
 }
 
 impl IOStream {
-    pub fn write(&mut self, buf: &[u8]) {
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), RuntimeError> {
         match self {
             IOStream::InMemoryBuffer(vec) => {
                 vec.extend_from_slice(buf);
+                Ok(())
             }
-            IOStream::Stdout(stdout) => {
-                stdout.write(buf).unwrap();
-            }
+            IOStream::Stdout(stdout) => stdout.write_all(buf).map_err(write_error),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), RuntimeError> {
+        match self {
+            IOStream::InMemoryBuffer(_) => Ok(()),
+            IOStream::Stdout(stdout) => stdout.flush().map_err(write_error),
         }
     }
 
@@ -25,3 +33,9 @@ impl IOStream {
         IOStream::Stdout(std::io::stdout())
     }
 }
+
+fn write_error(error: std::io::Error) -> RuntimeError {
+    RuntimeError::new_err(ErrorKind::IO {
+        message: format!("Could not write to stdout: {}", error),
+    })
+}
@@ -0,0 +1,254 @@
+use super::environment::{Context, Environment};
+use super::error::{ErrorKind, RuntimeError, TraceFrame};
+use super::value::Value;
+use super::{expect_bound, Continuation, Recovery, RuspResult, Step, StepResult};
+use crate::parser::ASTNode;
+use crate::span::Span;
+use std::rc::Rc;
+
+// Evaluation keeps its own stack of the work waiting on each value being
+// computed, rather than using the Rust stack, so deep recursion in a program
+// is limited only by Context::max_depth and never overflows.
+enum Frame {
+    // Waiting on the head of a call, children[0], to become a function.
+    Head {
+        env: Environment,
+        children: Rc<[ASTNode]>,
+        span: Span,
+    },
+    // Waiting on argument values.len() + 1 of a call to func.
+    Args {
+        env: Environment,
+        func: Value,
+        children: Rc<[ASTNode]>,
+        values: Vec<Value>,
+        span: Span,
+    },
+    // Waiting on a value for a special form to carry on with. span is where
+    // that value came from.
+    Then {
+        then: Continuation,
+        span: Span,
+    },
+    // Waiting on the result of a call to the closure name, made at span. This
+    // only records the call for backtraces; the value is passed straight on.
+    Call {
+        name: Rc<str>,
+        span: Span,
+    },
+    // Waiting on the value of a protected step, or for an error raised while
+    // carrying it out, to pass on to recovery.
+    Protect {
+        recovery: Recovery,
+    },
+}
+
+impl Frame {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Frame::Head { span, .. }
+            | Frame::Args { span, .. }
+            | Frame::Then { span, .. }
+            | Frame::Call { span, .. } => Some(*span),
+            Frame::Protect { .. } => None,
+        }
+    }
+}
+
+// Runs step, and everything it leads to, to completion.
+pub fn run(ctx: &mut Context, step: Step) -> RuspResult {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut step = step;
+    loop {
+        let next = match step {
+            Step::Done(value) => match stack.pop() {
+                Some(frame) => resume(ctx, &mut stack, frame, value),
+                None => return Ok(value),
+            },
+            Step::Eval(env, ast) => start(&mut stack, env, &ast),
+            Step::EvalThen(env, ast, then) => {
+                stack.push(Frame::Then {
+                    then,
+                    span: ast.span(),
+                });
+                Ok(Step::Eval(env, ast))
+            }
+            Step::Protect(step, recovery) => {
+                stack.push(Frame::Protect { recovery });
+                Ok(*step)
+            }
+        };
+        let mut next = match next {
+            Ok(_) if stack.len() > ctx.max_depth => RuntimeError::new(ErrorKind::StackOverflow {
+                max_depth: ctx.max_depth,
+            }),
+            next => next,
+        };
+        step = loop {
+            match next {
+                Ok(step) => break step,
+                Err(error) => match unwind(error, &mut stack) {
+                    (error, Some(recovery)) => next = recovery(ctx, Err(error)),
+                    (error, None) => return Err(error),
+                },
+            }
+        };
+    }
+}
+
+// Pops frames until one which recovers from errors, which is returned if
+// there is one. Gives error the location of the innermost frame popped, and
+// the calls popped as its backtrace.
+fn unwind(mut error: RuntimeError, stack: &mut Vec<Frame>) -> (RuntimeError, Option<Recovery>) {
+    while let Some(frame) = stack.pop() {
+        if let Some(span) = frame.span() {
+            error = error.or_at(span);
+        }
+        match frame {
+            Frame::Protect { recovery } => return (error, Some(recovery)),
+            Frame::Call { name, span } => {
+                let call = TraceFrame {
+                    name,
+                    call_site: span,
+                };
+                error = error.with_backtrace(std::iter::once(call));
+            }
+            _ => {}
+        }
+    }
+    (error, None)
+}
+
+// Begins evaluating ast.
+fn start(stack: &mut Vec<Frame>, env: Environment, ast: &ASTNode) -> StepResult {
+    match ast {
+        ASTNode::Terminal { token, span } => {
+            let value = Value::parse(token).map_err(|e| e.or_at(*span))?;
+            Ok(Step::Done(value))
+        }
+        ASTNode::Identifier { name, span } => env
+            .get(name)
+            .and_then(|value| expect_bound(value, name))
+            .map(Step::Done)
+            .map_err(|e| e.or_at(*span)),
+        ASTNode::LocalRef {
+            name,
+            depth,
+            index,
+            span,
+        } => env
+            .get_local(*depth, *index)
+            .and_then(|value| expect_bound(value, name))
+            .map(Step::Done)
+            .map_err(|e| e.or_at(*span)),
+        ASTNode::GlobalRef { name, span } => expect_bound(env.get_global(name), name)
+            .map(Step::Done)
+            .map_err(|e| e.or_at(*span)),
+        // The empty list, (), evaluates to itself just like nil.
+        ASTNode::SExpr { children, .. } if children.is_empty() => {
+            Ok(Step::Done(Value::List(Default::default())))
+        }
+        ASTNode::SExpr { children, span } => {
+            let head = children[0].clone();
+            stack.push(Frame::Head {
+                env: env.clone(),
+                children: children.clone(),
+                span: *span,
+            });
+            Ok(Step::Eval(env, head))
+        }
+        _ => Err(format!(
+            "Found ASTNode {:?} which should've been handled already.",
+            ast
+        )
+        .into()),
+    }
+}
+
+// Carries on with the work in frame now that value is ready for it.
+fn resume(ctx: &mut Context, stack: &mut Vec<Frame>, frame: Frame, value: Value) -> StepResult {
+    match frame {
+        Frame::Head {
+            env,
+            children,
+            span,
+        } => {
+            if !value.is_callable() {
+                return RuntimeError::new(ErrorKind::NotCallable { found: value })
+                    .map_err(|e| e.or_at(span));
+            }
+            if let Value::LazyFunction(func) = value {
+                return func(&env, ctx, &children[1..]).map_err(|e| e.or_at(span));
+            }
+            if children.len() == 1 {
+                return apply(&env, ctx, stack, &value, Vec::new(), span);
+            }
+            let first = children[1].clone();
+            stack.push(Frame::Args {
+                env: env.clone(),
+                func: value,
+                children,
+                values: Vec::new(),
+                span,
+            });
+            Ok(Step::Eval(env, first))
+        }
+        Frame::Args {
+            env,
+            func,
+            children,
+            mut values,
+            span,
+        } => {
+            values.push(value);
+            if values.len() + 1 == children.len() {
+                return apply(&env, ctx, stack, &func, values, span);
+            }
+            let next = children[values.len() + 1].clone();
+            stack.push(Frame::Args {
+                env: env.clone(),
+                func,
+                children,
+                values,
+                span,
+            });
+            Ok(Step::Eval(env, next))
+        }
+        Frame::Then { then, span } => then(ctx, value).map_err(|e| e.or_at(span)),
+        Frame::Call { .. } => Ok(Step::Done(value)),
+        Frame::Protect { recovery } => recovery(ctx, Ok(value)),
+    }
+}
+
+// Calls func with already evaluated args.
+fn apply(
+    env: &Environment,
+    ctx: &mut Context,
+    stack: &mut Vec<Frame>,
+    func: &Value,
+    args: Vec<Value>,
+    span: Span,
+) -> StepResult {
+    match func {
+        Value::Closure(closure) => {
+            let step = closure.invoke(ctx, &args).map_err(|e| e.or_at(span))?;
+            let call = Frame::Call {
+                name: closure.name(),
+                span,
+            };
+            // Nothing else is waiting on a call made in tail position, so it
+            // takes the place of the call it was made from and the stack
+            // doesn't grow.
+            match stack.last_mut() {
+                Some(top @ Frame::Call { .. }) => *top = call,
+                _ => stack.push(call),
+            }
+            Ok(step)
+        }
+        Value::Function(func) => func(env, ctx, &args).map(Step::Done),
+        _ => RuntimeError::new(ErrorKind::NotCallable {
+            found: func.clone(),
+        }),
+    }
+    .map_err(|e| e.or_at(span))
+}
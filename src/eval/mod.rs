@@ -1,175 +1,174 @@
 pub mod builtins;
 pub mod environment;
 pub mod error;
+pub mod expander;
 pub mod io;
+mod machine;
+pub mod optimizer;
+pub mod resolver;
+pub mod scope;
+pub mod syntax_rules;
 pub mod value;
 
 use environment::Context;
 use environment::Environment;
-use error::RuntimeError;
+use error::{ErrorKind, RuntimeError};
 
 use crate::parser::ASTNode;
-use crate::parser::ASTNode::{Identifier, Program, SExpr, Terminal};
+use crate::parser::ASTNode::Program;
+use crate::span::Span;
 
 use value::Value;
 
 pub type RuspResult = Result<Value, RuntimeError>;
 
+// What evaluating a special form or calling a closure produces. Rather than
+// evaluating an expression in tail position themselves, they hand it back as
+// Eval so that eval can carry on in a loop instead of recursing. This way
+// tail calls, including mutually recursive ones, use no extra stack.
+pub enum Step {
+    Done(Value),
+    Eval(Environment, ASTNode),
+    // Evaluate the expression and pass its value on to the continuation. This
+    // is how special forms evaluate expressions which aren't in tail position.
+    EvalThen(Environment, ASTNode, Continuation),
+    // Carry on with the step, then pass what it results in, value or error,
+    // on to the recovery. This is how try catches errors.
+    Protect(Box<Step>, Recovery),
+}
+
+pub type StepResult = Result<Step, RuntimeError>;
+
+pub type Continuation = Box<dyn FnOnce(&mut Context, Value) -> StepResult>;
+
+pub type Recovery = Box<dyn FnOnce(&mut Context, RuspResult) -> StepResult>;
+
+// Like Continuation, but for the values of several expressions. See eval_each.
+type ListContinuation = Box<dyn FnOnce(&mut Context, Vec<Value>) -> StepResult>;
+
 pub fn default_env() -> environment::Environment {
-    let mut env = environment::Environment::new();
-    env.insert("<", Value::Function(builtins::less_than));
-    env.insert("write", Value::Function(builtins::write_impl));
-    env.insert("if", Value::LazyFunction(builtins::if_impl));
-    env.insert("let", Value::LazyFunction(builtins::let_impl));
-    env.insert("lambda", Value::LazyFunction(builtins::lambda));
-    env.insert("+", Value::Function(builtins::plus));
-    env.insert("-", Value::Function(builtins::minus));
-    env.insert("str", Value::Function(builtins::to_str));
-    env.insert("list", Value::LazyFunction(builtins::list));
-    env.insert("readline", Value::Function(builtins::readline));
-    env.insert("defun", Value::EnvMutatingFunction(builtins::defun));
+    let env = environment::Environment::new();
+    env.define("<", Value::Function(builtins::less_than));
+    env.define("write", Value::Function(builtins::write_impl));
+    env.define("if", Value::LazyFunction(builtins::if_impl));
+    env.define("let", Value::LazyFunction(builtins::let_impl));
+    env.define("lambda", Value::LazyFunction(builtins::lambda));
+    env.define("+", Value::Function(builtins::plus));
+    env.define("-", Value::Function(builtins::minus));
+    env.define("str", Value::Function(builtins::to_str));
+    env.define("list", Value::Function(builtins::list));
+    env.define("readline", Value::Function(builtins::readline));
+    env.define("error", Value::Function(builtins::error));
+    env.define("throw", Value::Function(builtins::throw));
+    env.define("raise", Value::Function(builtins::raise));
+    env.define("error-kind", Value::Function(builtins::error_kind));
+    env.define("error-message", Value::Function(builtins::error_message));
+    env.define("error-data", Value::Function(builtins::error_data));
+    env.define("try", Value::LazyFunction(builtins::try_impl));
+    env.define(
+        "unwind-protect",
+        Value::LazyFunction(builtins::unwind_protect),
+    );
+    env.define("defun", Value::LazyFunction(builtins::defun));
+    env.define("define", Value::LazyFunction(builtins::define));
+    env.define("set!", Value::LazyFunction(builtins::set));
+    env.define("quote", Value::LazyFunction(builtins::quote));
+    env.define("quasiquote", Value::LazyFunction(builtins::quasiquote));
+    env.define("unquote", Value::LazyFunction(builtins::unquote));
+    env.define(
+        "unquote-splicing",
+        Value::LazyFunction(builtins::unquote_splicing),
+    );
+    env.define("eval", Value::LazyFunction(builtins::eval_impl));
+    env.define("macroexpand-1", Value::Function(builtins::macroexpand_1));
+    env.define("macroexpand", Value::Function(builtins::macroexpand));
     env
 }
 
 pub fn eval_program(
-    env: &mut Environment,
+    env: &Environment,
     ctx: &mut Context,
     ast: &ASTNode,
 ) -> Result<Value, RuntimeError> {
     match ast {
-        Program { statements } => {
+        Program { statements, .. } => {
+            let mut last = Value::Unit;
             for statement in statements {
-                eval_program(env, ctx, statement)?;
+                last = eval_program(env, ctx, statement)?;
             }
-            Ok(Value::Unit)
+            Ok(last)
         }
-        node => eval_maybe_mutate_env(env, ctx, node),
+        node => eval(env, ctx, node),
     }
 }
 
-fn extract_identifier(node: &ASTNode) -> Option<String> {
-    if let ASTNode::Identifier { name } = node {
-        Some(name.to_owned())
-    } else {
-        None
-    }
+pub fn eval(env: &Environment, ctx: &mut Context, ast: &ASTNode) -> Result<Value, RuntimeError> {
+    machine::run(ctx, Step::Eval(env.clone(), ast.clone()))
 }
 
-// We first check if ast represents a callable which needs to mutate its passed
-// environment. This is narrowly for 'defun'. Otherwise we delegate to the
-// immutable env eval.
-fn eval_maybe_mutate_env(
-    env: &mut Environment,
-    ctx: &mut Context,
-    ast: &ASTNode,
-) -> Result<Value, RuntimeError> {
-    match ast {
-        SExpr { children } => {
-            // Try to lookup the first element of ast as an EnvMutatingFunction.
-            // If we can't do that, then we will just run 'eval' instead.
-            let maybe_callable = extract_identifier(&children[0])
-                .and_then(|name| env.get(&name))
-                .and_then(|value| {
-                    if let Value::EnvMutatingFunction(f) = value {
-                        Some(f)
-                    } else {
-                        None
-                    }
-                });
-            if let Some(env_mutating_func) = maybe_callable {
-                env_mutating_func(env, &children[1..])
-            } else {
-                eval(env, ctx, ast)
-            }
-        }
-        _ => eval(env, ctx, ast),
-    }
+// Runs step, and everything it leads to, to completion.
+pub fn run_step(ctx: &mut Context, step: Step) -> RuspResult {
+    machine::run(ctx, step)
 }
 
-pub fn eval(env: &Environment, ctx: &mut Context, ast: &ASTNode) -> Result<Value, RuntimeError> {
-    match ast {
-        Terminal { token } => {
-            let value = Value::parse(token)?;
-            Ok(value)
-        }
-        Identifier { name } => resolve_identifier(env, name),
-        SExpr { children } => {
-            let func_name = eval_expect_callable(env, ctx, &children[0])?;
-
-            let val = eval_function(env, ctx, &func_name, &children[1..])?;
+// Evaluates each of exprs in turn, then passes their values on to then.
+pub fn eval_each<F>(ctx: &mut Context, env: Environment, exprs: Vec<ASTNode>, then: F) -> StepResult
+where
+    F: FnOnce(&mut Context, Vec<Value>) -> StepResult + 'static,
+{
+    eval_rest(ctx, env, exprs.into_iter(), Vec::new(), Box::new(then))
+}
 
-            Ok(val)
-        }
-        _ => RuntimeError::new(&format!(
-            "Found ASTNode {:?} which should've been handled already.",
-            ast
+fn eval_rest(
+    ctx: &mut Context,
+    env: Environment,
+    mut exprs: std::vec::IntoIter<ASTNode>,
+    mut values: Vec<Value>,
+    then: ListContinuation,
+) -> StepResult {
+    match exprs.next() {
+        Some(expr) => Ok(Step::EvalThen(
+            env.clone(),
+            expr,
+            Box::new(move |ctx, value| {
+                values.push(value);
+                eval_rest(ctx, env, exprs, values, then)
+            }),
         )),
+        None => then(ctx, values),
     }
 }
 
-fn eval_expect_callable(
+// Turns data passed to eval into code ready to evaluate in env, by expanding
+// the macros used in it and resolving it. span is given to every node.
+pub fn code_from_data(
+    data: &Value,
     env: &Environment,
     ctx: &mut Context,
-    arg: &ASTNode,
-) -> Result<Value, RuntimeError> {
-    let value = eval(env, ctx, arg)?;
-    if value.is_callable() {
-        Ok(value)
-    } else {
-        RuntimeError::new(&format!(
-            "First argument, {:?} to function call is not a \
-                 function value.",
-            arg
-        ))
-    }
+    span: Span,
+) -> Result<ASTNode, RuntimeError> {
+    let code = expander::expand(&data.to_ast(span)?, env, ctx)?;
+    Ok(resolver::resolve(&code, env)?)
 }
 
-// Looks up identifier in env and fails if it's not found.
-fn resolve_identifier(env: &Environment, identifier: &str) -> Result<Value, RuntimeError> {
-    let maybe_value = env.get(identifier);
-    if let Some(value) = maybe_value {
-        Ok(value.clone())
+// Takes whether assigning to identifier found a binding and fails if not.
+pub fn expect_assigned(found: bool, identifier: &str) -> Result<(), RuntimeError> {
+    if found {
+        Ok(())
     } else {
-        RuntimeError::new(&format!(
-            "Failed to find identifier {:?} in environment.",
-            identifier
-        ))
-    }
-}
-
-// Converts args to a list of Values.
-fn resolve_args(
-    env: &Environment,
-    ctx: &mut Context,
-    args: &[ASTNode],
-) -> Result<Vec<Value>, RuntimeError> {
-    let mut arg_values = Vec::new();
-    for arg in args {
-        let arg_val = eval(env, ctx, arg)?;
-        arg_values.push(arg_val);
+        RuntimeError::new(ErrorKind::UnboundAssignment {
+            name: identifier.to_owned(),
+        })
     }
-    Ok(arg_values)
 }
 
-fn eval_function(
-    env: &Environment,
-    ctx: &mut Context,
-    func: &Value,
-    args: &[ASTNode],
-) -> Result<Value, RuntimeError> {
-    match func {
-        Value::Closure(closure) => {
-            let args = resolve_args(env, ctx, args)?;
-            closure.invoke(env, ctx, &args)
-        }
-        Value::Function(func) => {
-            let args = resolve_args(env, ctx, args)?;
-            func(env, ctx, &args)
-        }
-        Value::LazyFunction(func) => func(env, ctx, args),
-        _ => RuntimeError::new(&format!(
-            "Could not evaluate {:?} as a function call.",
-            func
-        )),
+// Takes the result of looking up identifier and fails if it wasn't found.
+pub fn expect_bound(value: Option<Value>, identifier: &str) -> Result<Value, RuntimeError> {
+    if let Some(value) = value {
+        Ok(value)
+    } else {
+        RuntimeError::new(ErrorKind::UnboundIdentifier {
+            name: identifier.to_owned(),
+        })
     }
 }
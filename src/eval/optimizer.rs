@@ -0,0 +1,502 @@
+use super::builtins::try_clause;
+use super::environment::{Context, Environment};
+use super::io::IOStream;
+use super::resolver::global_definitions;
+use super::scope::{definition_name, is_special_form, param_names, Binding, Scopes};
+use super::syntax_rules::original_name;
+use super::value::Value;
+use crate::parser::ASTNode;
+use crate::span::Span;
+use std::collections::{HashMap, HashSet};
+
+// Builtins without side effects, and the number of arguments they take. Calls
+// to these on literals are worked out before running.
+const PURE_BUILTINS: &[(&str, usize)] = &[("+", 2), ("-", 2), ("<", 2), ("str", 1)];
+
+// Functions whose body has at most this many nodes are inlined.
+const INLINE_LIMIT: usize = 24;
+
+// Rewrites ast, which hasn't been resolved yet, into an equivalent but
+// cheaper program:
+//
+// - Calls to pure builtins on literals are replaced by their result.
+// - An if whose condition is a literal is replaced by the branch it takes.
+// - A let binding a literal is replaced by its body with the literal in place
+//   of the name.
+// - Calls to small functions defined once at the top level are replaced by
+//   their body, with each argument bound by a let to a name which can't
+//   clash with anything in the program.
+//
+// Anything which would fail is left alone so it fails when run, as before.
+// Only whole programs get inlining, since otherwise a later form might
+// redefine the function. Globals assigned with set! count as redefined.
+pub fn optimize(ast: &ASTNode, env: &Environment) -> ASTNode {
+    let mut definitions = HashMap::new();
+    if let ASTNode::Program { statements, .. } = ast {
+        let mut names = global_definitions(ast);
+        for statement in statements {
+            assigned(statement, &mut names);
+        }
+        for name in names {
+            *definitions.entry(name).or_insert(0) += 1;
+        }
+    }
+    let mut optimizer = Optimizer {
+        env,
+        ctx: Context::new(IOStream::new_in_memory_buffer()),
+        scopes: Scopes::new(env),
+        definitions,
+        inlinable: HashMap::new(),
+        renamed: 0,
+    };
+    optimizer.optimize(ast)
+}
+
+// A function which calls can be replaced by the body of.
+struct Inlinable {
+    params: Vec<String>,
+    body: ASTNode,
+    // Every identifier in body other than params.
+    free: HashSet<String>,
+}
+
+struct Optimizer<'a> {
+    env: &'a Environment,
+    // Used to call pure builtins. Nothing is ever written to it.
+    ctx: Context,
+    scopes: Scopes<'a>,
+    // How many times each global is defined in the program.
+    definitions: HashMap<String, usize>,
+    inlinable: HashMap<String, Inlinable>,
+    // The number of parameters renamed by inlining so far, used to make the
+    // new names unique.
+    renamed: usize,
+}
+
+impl<'a> Optimizer<'a> {
+    fn optimize(&mut self, node: &ASTNode) -> ASTNode {
+        match node {
+            ASTNode::Program { statements, span } => {
+                let mut optimized = Vec::new();
+                for statement in statements {
+                    let statement = self.optimize(statement);
+                    if let Some((name, inlinable)) = self.inlinable_definition(&statement) {
+                        self.inlinable.insert(name, inlinable);
+                    }
+                    optimized.push(statement);
+                }
+                ASTNode::Program {
+                    statements: optimized,
+                    span: *span,
+                }
+            }
+            ASTNode::SExpr { children, span } if !children.is_empty() => {
+                self.optimize_form(children, *span)
+            }
+            _ => node.clone(),
+        }
+    }
+
+    fn optimize_all(&mut self, nodes: &[ASTNode]) -> Vec<ASTNode> {
+        nodes.iter().map(|node| self.optimize(node)).collect()
+    }
+
+    fn optimize_in_scope(&mut self, names: Vec<String>, nodes: &[ASTNode]) -> Vec<ASTNode> {
+        self.scopes.push(names);
+        let optimized = self.optimize_all(nodes);
+        self.scopes.pop();
+        optimized
+    }
+
+    // Optimizes the non-empty s-expression children. Malformed special forms
+    // are optimized like function calls and left for evaluation to report.
+    fn optimize_form(&mut self, children: &[ASTNode], span: Span) -> ASTNode {
+        let head = &children[0];
+        let args = &children[1..];
+        let mut optimized = vec![head.clone()];
+        let form = self.scopes.special_form(head);
+        if let Some(binding) = form.and_then(|form| Binding::of(form, args)) {
+            return self.optimize_binding(head, binding, span);
+        }
+        match (form, args) {
+            (Some("quote" | "quasiquote" | "unquote" | "unquote-splicing"), _) => {
+                optimized.extend_from_slice(args)
+            }
+            (Some("if"), [condition, then_branch, else_branch]) => {
+                let condition = self.optimize(condition);
+                match Value::from_ast(&condition) {
+                    Ok(Value::Boolean(true)) if is_literal(&condition) => {
+                        return self.optimize(then_branch)
+                    }
+                    Ok(Value::Boolean(false)) if is_literal(&condition) => {
+                        return self.optimize(else_branch)
+                    }
+                    _ => {}
+                }
+                optimized.push(condition);
+                optimized.extend(self.optimize_all(&[then_branch.clone(), else_branch.clone()]));
+            }
+            (Some("try"), _) => {
+                for arg in args {
+                    let arg = self.optimize_try_part(arg);
+                    optimized.push(arg);
+                }
+            }
+            (Some(_), _) => optimized.extend(self.optimize_all(args)),
+            (None, _) => {
+                let args = self.optimize_all(args);
+                if let Some(folded) = self.fold(head, &args, span) {
+                    return folded;
+                }
+                if let Some(inlined) = self.inline(head, &args, span) {
+                    return inlined;
+                }
+                optimized.extend(args);
+            }
+        }
+        ASTNode::SExpr {
+            children: optimized.into(),
+            span,
+        }
+    }
+
+    // Optimizes a form, with head and binding, which binds names.
+    fn optimize_binding(&mut self, head: &ASTNode, binding: Binding, span: Span) -> ASTNode {
+        let mut optimized = vec![head.clone()];
+        match binding {
+            Binding::Let {
+                id,
+                name,
+                value,
+                body,
+            } => {
+                let value = self.optimize(value);
+                if is_literal(&value) && !refers_by_name(body) && !is_special_form(self.env, name) {
+                    let substitutions = HashMap::from([(name.to_owned(), value)]);
+                    return self.optimize(&substitute(self.env, body, &substitutions));
+                }
+                optimized.push(id.clone());
+                optimized.push(value);
+                optimized.extend(
+                    self.optimize_in_scope(vec![name.to_owned()], std::slice::from_ref(body)),
+                );
+            }
+            Binding::Function {
+                name,
+                params,
+                names,
+                body,
+            } => {
+                optimized.extend(name.cloned());
+                optimized.push(params.clone());
+                optimized.extend(self.optimize_in_scope(names, body));
+            }
+            Binding::Catch { id, name, handler } => {
+                optimized.push(id.clone());
+                optimized.extend(self.optimize_in_scope(vec![name.to_owned()], handler));
+            }
+        }
+        ASTNode::SExpr {
+            children: optimized.into(),
+            span,
+        }
+    }
+
+    // Optimizes an argument to try. The name in a catch clause is bound for
+    // the rest of the clause.
+    fn optimize_try_part(&mut self, arg: &ASTNode) -> ASTNode {
+        let (head, (clause, rest)) = match (arg, try_clause(arg)) {
+            (ASTNode::SExpr { children, .. }, Some(clause)) => (&children[0], clause),
+            _ => return self.optimize(arg),
+        };
+        if let Some(binding) = Binding::of(clause, rest) {
+            return self.optimize_binding(head, binding, arg.span());
+        }
+        let mut optimized = vec![head.clone()];
+        optimized.extend(self.optimize_all(rest));
+        ASTNode::SExpr {
+            children: optimized.into(),
+            span: arg.span(),
+        }
+    }
+
+    // If head is a pure builtin and args are all literals, returns the
+    // result of the call as a literal.
+    fn fold(&mut self, head: &ASTNode, args: &[ASTNode], span: Span) -> Option<ASTNode> {
+        let name = self.scopes.global_name(head)?;
+        if self.definitions.contains_key(name)
+            || !PURE_BUILTINS.contains(&(name, args.len()))
+            || !args.iter().all(is_literal)
+        {
+            return None;
+        }
+        let func = match self.env.get_global(name) {
+            Some(Value::Function(func)) => func,
+            _ => return None,
+        };
+        let values: Vec<Value> = args
+            .iter()
+            .map(Value::from_ast)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        match func(self.env, &mut self.ctx, &values).ok()? {
+            value @ (Value::Int(_)
+            | Value::Float(_)
+            | Value::Boolean(_)
+            | Value::Char(_)
+            | Value::Str(_)) => value.to_ast(span).ok(),
+            _ => None,
+        }
+    }
+
+    // If head is an inlinable function which can be called with args here,
+    // returns its body with args substituted in.
+    fn inline(&mut self, head: &ASTNode, args: &[ASTNode], span: Span) -> Option<ASTNode> {
+        let name = self.scopes.global_name(head)?;
+        let function = self.inlinable.get(name)?;
+        // The body's identifiers, and the lets binding the arguments, must
+        // refer to the same things here as where the function was defined.
+        if function.params.len() != args.len()
+            || function.free.iter().any(|name| self.scopes.is_local(name))
+            || self.scopes.is_local("let")
+        {
+            return None;
+        }
+        // Literals are substituted directly so the body can be folded. Other
+        // arguments are bound by lets to keep them evaluated once, in order.
+        let mut substitutions = HashMap::new();
+        let mut bindings = Vec::new();
+        for (param, arg) in function.params.iter().zip(args) {
+            if is_literal(arg) {
+                substitutions.insert(param.to_owned(), arg.clone());
+            } else {
+                self.renamed += 1;
+                // Commas can't appear in identifiers, so this can't clash.
+                let renamed = format!("{},{}", param, self.renamed);
+                let identifier = ASTNode::Identifier {
+                    name: renamed.clone(),
+                    span,
+                };
+                substitutions.insert(param.to_owned(), identifier);
+                bindings.push((renamed, arg.clone()));
+            }
+        }
+        let body = substitute(self.env, &function.body, &substitutions);
+        let names: Vec<String> = bindings.iter().map(|(name, _)| name.to_owned()).collect();
+        let body = self.optimize_in_scope(names, &[body]).remove(0);
+        Some(
+            bindings
+                .into_iter()
+                .rev()
+                .fold(body, |body, (name, value)| {
+                    let identifier = |name: &str| ASTNode::Identifier {
+                        name: name.to_owned(),
+                        span,
+                    };
+                    ASTNode::SExpr {
+                        children: vec![identifier("let"), identifier(&name), value, body].into(),
+                        span,
+                    }
+                }),
+        )
+    }
+
+    // If statement defines a function calls to which can be inlined, returns
+    // its name and how to inline it.
+    fn inlinable_definition(&self, statement: &ASTNode) -> Option<(String, Inlinable)> {
+        let children = match statement {
+            ASTNode::SExpr { children, .. } => children,
+            _ => return None,
+        };
+        let (name, params, body) = match &children[..] {
+            [head, ASTNode::Identifier { name, .. }, params, body]
+                if self.scopes.special_form(head) == Some("defun") =>
+            {
+                (name, param_names(params)?, body)
+            }
+            _ => return None,
+        };
+        // Definitions in the body would be global once it's inlined.
+        if self.definitions.get(name) != Some(&1)
+            || definition_name(body).is_some()
+            || params.iter().any(|param| is_special_form(self.env, param))
+            || size(body) > INLINE_LIMIT
+        {
+            return None;
+        }
+        let mut free = HashSet::new();
+        identifiers(body, &mut free);
+        for param in &params {
+            free.remove(param);
+        }
+        // Calls to other functions in the program may lead back here.
+        if refers_by_name(body) || free.iter().any(|id| self.definitions.contains_key(id)) {
+            return None;
+        }
+        Some((
+            name.to_owned(),
+            Inlinable {
+                params,
+                body: body.clone(),
+                free,
+            },
+        ))
+    }
+}
+
+fn is_literal(node: &ASTNode) -> bool {
+    matches!(node, ASTNode::Terminal { .. })
+}
+
+// Whether node may look up variables by name at runtime, which eval and
+// quasiquote can do, or assign them with set!. Variables in such code can't
+// be renamed or replaced.
+fn refers_by_name(node: &ASTNode) -> bool {
+    let mut ids = HashSet::new();
+    identifiers(node, &mut ids);
+    ids.contains("eval") || ids.contains("quasiquote") || ids.contains("set!")
+}
+
+// Adds the name of every variable assigned with set! in node to names.
+fn assigned(node: &ASTNode, names: &mut Vec<String>) {
+    if let ASTNode::SExpr { children, .. } = node {
+        if let [ASTNode::Identifier { name: head, .. }, ASTNode::Identifier { name, .. }, _] =
+            &children[..]
+        {
+            if head == "set!" {
+                names.push(name.to_owned());
+            }
+        }
+        for child in children.iter() {
+            assigned(child, names);
+        }
+    }
+}
+
+// The number of nodes in node.
+fn size(node: &ASTNode) -> usize {
+    match node {
+        ASTNode::SExpr { children, .. } => 1 + children.iter().map(size).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+// Adds the name of every identifier in node to ids.
+fn identifiers(node: &ASTNode, ids: &mut HashSet<String>) {
+    match node {
+        ASTNode::Identifier { name, .. } => {
+            ids.insert(name.to_owned());
+        }
+        ASTNode::SExpr { children, .. } => {
+            for child in children.iter() {
+                identifiers(child, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Replaces the identifiers in node named in substitutions, other than those
+// shadowed by a let, function or catch inside node, quoted or being defined.
+fn substitute(
+    env: &Environment,
+    node: &ASTNode,
+    substitutions: &HashMap<String, ASTNode>,
+) -> ASTNode {
+    let children = match node {
+        ASTNode::Identifier { name, .. } => {
+            return substitutions
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| node.clone())
+        }
+        ASTNode::SExpr { children, .. } if !children.is_empty() => children,
+        _ => return node.clone(),
+    };
+    let head = match &children[0] {
+        ASTNode::Identifier { name, .. } if is_special_form(env, original_name(name)) => {
+            Some(original_name(name))
+        }
+        _ => None,
+    };
+    let all = |nodes: &[ASTNode], substitutions: &HashMap<String, ASTNode>| {
+        nodes
+            .iter()
+            .map(|child| substitute(env, child, substitutions))
+            .collect::<Vec<_>>()
+    };
+    let without = |names: &[String]| {
+        let mut inner = substitutions.clone();
+        for name in names {
+            inner.remove(name);
+        }
+        inner
+    };
+    // The arguments of a form binding names, substituted.
+    let bound = |binding: Binding| match binding {
+        Binding::Let {
+            id,
+            name,
+            value,
+            body,
+        } => {
+            let mut substituted = vec![id.clone()];
+            substituted.extend(all(std::slice::from_ref(value), substitutions));
+            substituted.extend(all(
+                std::slice::from_ref(body),
+                &without(&[name.to_owned()]),
+            ));
+            substituted
+        }
+        Binding::Function {
+            name,
+            params,
+            names,
+            body,
+        } => {
+            let mut substituted: Vec<ASTNode> = name.into_iter().cloned().collect();
+            substituted.push(params.clone());
+            substituted.extend(all(body, &without(&names)));
+            substituted
+        }
+        Binding::Catch { id, name, handler } => {
+            let mut substituted = vec![id.clone()];
+            substituted.extend(all(handler, &without(&[name.to_owned()])));
+            substituted
+        }
+    };
+    let args = &children[1..];
+    let mut substituted = vec![children[0].clone()];
+    match (head, head.and_then(|form| Binding::of(form, args)), args) {
+        (Some("quote"), _, _) => return node.clone(),
+        (_, Some(binding), _) => substituted.extend(bound(binding)),
+        (Some("define"), _, [name, value]) => {
+            substituted.push(name.clone());
+            substituted.extend(all(std::slice::from_ref(value), substitutions));
+        }
+        (Some("try"), _, _) => {
+            for arg in args {
+                let arg = match (arg, try_clause(arg)) {
+                    (ASTNode::SExpr { children, span }, Some((clause, rest))) => {
+                        let mut clause_children = vec![children[0].clone()];
+                        match Binding::of(clause, rest) {
+                            Some(binding) => clause_children.extend(bound(binding)),
+                            None => clause_children.extend(all(rest, substitutions)),
+                        }
+                        ASTNode::SExpr {
+                            children: clause_children.into(),
+                            span: *span,
+                        }
+                    }
+                    _ => substitute(env, arg, substitutions),
+                };
+                substituted.push(arg);
+            }
+        }
+        _ => substituted = all(children, substitutions),
+    }
+    ASTNode::SExpr {
+        children: substituted.into(),
+        span: node.span(),
+    }
+}
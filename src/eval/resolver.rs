@@ -0,0 +1,406 @@
+use super::builtins::{form_argument, try_clause};
+use super::environment::Environment;
+use super::error::{ErrorKind, RuntimeError};
+use super::scope::{definition_name, Binding, Scopes};
+use super::syntax_rules::original_name;
+use crate::error::InterpreterError;
+use crate::parser::ASTNode;
+use crate::span::Span;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct ResolveError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl From<ResolveError> for InterpreterError {
+    fn from(resolve_error: ResolveError) -> InterpreterError {
+        InterpreterError::new("ResolveError", &resolve_error.message).at(resolve_error.span)
+    }
+}
+
+impl From<Vec<ResolveError>> for InterpreterError {
+    fn from(resolve_errors: Vec<ResolveError>) -> InterpreterError {
+        let mut errors = resolve_errors.into_iter().map(InterpreterError::from);
+        let first = errors
+            .next()
+            .unwrap_or_else(|| InterpreterError::new("ResolveError", "Unknown resolve error."));
+        errors.fold(first, InterpreterError::and)
+    }
+}
+
+impl From<ResolveError> for RuntimeError {
+    fn from(resolve_error: ResolveError) -> RuntimeError {
+        RuntimeError::from(resolve_error.message).or_at(resolve_error.span)
+    }
+}
+
+// Only the first error is reported, as for any other runtime error.
+impl From<Vec<ResolveError>> for RuntimeError {
+    fn from(resolve_errors: Vec<ResolveError>) -> RuntimeError {
+        resolve_errors
+            .into_iter()
+            .next()
+            .map(RuntimeError::from)
+            .unwrap_or_else(|| "Unknown resolve error.".to_owned().into())
+    }
+}
+
+// Works out what every identifier in ast, which is about to be evaluated in
+// env, refers to. Identifiers bound by an enclosing let or function become
+// LocalRefs to the slot their value will be in, and the rest GlobalRefs.
+// Identifiers which can't be bound by the time they're evaluated are
+// reported, so mistakes are found before anything runs. An identifier
+// renamed by a syntax-rules template is only local if the expansion bound
+// it. Otherwise it refers to the global with its original name, even where
+// a local binding of that name would capture it.
+//
+// Function bodies may refer to globals defined after the function. When ast
+// is a whole Program these must be defined somewhere in it. Otherwise ast is
+// one form of a longer input, so anything is allowed since the rest of the
+// input might define it.
+pub fn resolve(ast: &ASTNode, env: &Environment) -> Result<ASTNode, Vec<ResolveError>> {
+    let declared = match ast {
+        ASTNode::Program { .. } => Some(global_definitions(ast).into_iter().collect()),
+        _ => None,
+    };
+    resolve_with(ast, env, declared)
+}
+
+// Like resolve, but accepts the same programs as resolving their forms one
+// at a time would, so function bodies may refer to any global. Used to run a
+// whole program the way run_reader would have.
+pub fn resolve_forms(ast: &ASTNode, env: &Environment) -> Result<ASTNode, Vec<ResolveError>> {
+    resolve_with(ast, env, None)
+}
+
+fn resolve_with(
+    ast: &ASTNode,
+    env: &Environment,
+    declared: Option<HashSet<String>>,
+) -> Result<ASTNode, Vec<ResolveError>> {
+    let mut resolver = Resolver {
+        env,
+        scopes: Scopes::new(env),
+        defined: HashSet::new(),
+        declared,
+        function_depth: 0,
+        errors: Vec::new(),
+    };
+    let resolved = resolver.resolve(ast);
+    if resolver.errors.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+// The names of the globals defined in node, once for each definition. That's
+// every definition not directly in a function's body, including any which
+// might not run.
+pub fn global_definitions(node: &ASTNode) -> Vec<String> {
+    let mut names = Vec::new();
+    add_global_definitions(node, false, &mut names);
+    names
+}
+
+fn add_global_definitions(node: &ASTNode, in_body: bool, names: &mut Vec<String>) {
+    let children = match node {
+        ASTNode::Program { statements, .. } => statements.as_slice(),
+        ASTNode::SExpr { children, .. } => children,
+        _ => return,
+    };
+    if let (false, Some(name)) = (in_body, definition_name(node)) {
+        names.push(name);
+    }
+    let body = match children.first() {
+        Some(ASTNode::Identifier { name, .. }) => {
+            match Binding::of(original_name(name), &children[1..]) {
+                Some(Binding::Function { body, .. }) => children.len() - body.len(),
+                _ => children.len(),
+            }
+        }
+        _ => children.len(),
+    };
+    for (i, child) in children.iter().enumerate() {
+        add_global_definitions(child, i >= body, names);
+    }
+}
+
+// Replaces the argument of a form like (unquote x) with arg.
+pub fn with_argument(form: &ASTNode, arg: ASTNode) -> ASTNode {
+    match form {
+        ASTNode::SExpr { children, span } => ASTNode::SExpr {
+            children: vec![children[0].clone(), arg].into(),
+            span: *span,
+        },
+        _ => form.clone(),
+    }
+}
+
+struct Resolver<'a> {
+    env: &'a Environment,
+    scopes: Scopes<'a>,
+    // Globals defined by top level forms already resolved.
+    defined: HashSet<String>,
+    // Globals defined anywhere in the program, or None if more input may
+    // follow. See resolve.
+    declared: Option<HashSet<String>>,
+    // The number of function bodies enclosing the current expression.
+    function_depth: usize,
+    errors: Vec<ResolveError>,
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve(&mut self, node: &ASTNode) -> ASTNode {
+        match node {
+            ASTNode::Program { statements, span } => {
+                let mut resolved = Vec::new();
+                for statement in statements {
+                    resolved.push(self.resolve(statement));
+                    self.defined.extend(global_definitions(statement));
+                }
+                ASTNode::Program {
+                    statements: resolved,
+                    span: *span,
+                }
+            }
+            ASTNode::Identifier { name, span } => self.resolve_identifier(name, *span),
+            ASTNode::SExpr { children, span } if !children.is_empty() => {
+                let children = self.resolve_form(children);
+                ASTNode::SExpr {
+                    children: children.into(),
+                    span: *span,
+                }
+            }
+            _ => node.clone(),
+        }
+    }
+
+    fn resolve_all(&mut self, nodes: &[ASTNode]) -> Vec<ASTNode> {
+        nodes.iter().map(|node| self.resolve(node)).collect()
+    }
+
+    // Resolves nodes as the body of a function or let binding names.
+    fn resolve_in_scope(&mut self, names: Vec<String>, nodes: &[ASTNode]) -> Vec<ASTNode> {
+        self.scopes.push(names);
+        let resolved = self.resolve_all(nodes);
+        self.scopes.pop();
+        resolved
+    }
+
+    fn resolve_function_body(&mut self, names: Vec<String>, body: &[ASTNode]) -> Vec<ASTNode> {
+        self.function_depth += 1;
+        self.scopes.push(names);
+        let resolved = body
+            .iter()
+            .map(|statement| {
+                let resolved = self.resolve(statement);
+                match definition_name(statement) {
+                    Some(_) => self.resolve_defined_name(resolved),
+                    None => resolved,
+                }
+            })
+            .collect();
+        self.scopes.pop();
+        self.function_depth -= 1;
+        resolved
+    }
+
+    // Makes the name defined by form, a definition in a function's body,
+    // refer to the slot it's bound in.
+    fn resolve_defined_name(&mut self, form: ASTNode) -> ASTNode {
+        match form {
+            ASTNode::SExpr { children, span } => {
+                let mut children = children.to_vec();
+                if let ASTNode::Identifier { name, span } = &children[1] {
+                    children[1] = self.resolve_identifier(name, *span);
+                }
+                ASTNode::SExpr {
+                    children: children.into(),
+                    span,
+                }
+            }
+            _ => form,
+        }
+    }
+
+    fn resolve_identifier(&mut self, name: &str, span: Span) -> ASTNode {
+        if let Some((depth, index)) = self.scopes.position(name) {
+            return ASTNode::LocalRef {
+                name: Rc::from(name),
+                depth,
+                index,
+                span,
+            };
+        }
+        let name = original_name(name);
+        if !self.global_may_be_bound(name) {
+            self.errors.push(ResolveError {
+                message: format!("Found unbound identifier {:?}.", name),
+                span,
+            });
+        }
+        ASTNode::GlobalRef {
+            name: Rc::from(name),
+            span,
+        }
+    }
+
+    // Resolves the identifier set! assigns to, which must already be bound.
+    fn resolve_assigned(&mut self, name: &str, span: Span) -> ASTNode {
+        let errors = self.errors.len();
+        let resolved = self.resolve_identifier(name, span);
+        if self.errors.len() > errors {
+            self.errors.truncate(errors);
+            let name = original_name(name).to_owned();
+            self.errors.push(ResolveError {
+                message: ErrorKind::UnboundAssignment { name }.to_string(),
+                span,
+            });
+        }
+        resolved
+    }
+
+    fn global_may_be_bound(&self, name: &str) -> bool {
+        if self.env.get_global(name).is_some() || self.defined.contains(name) {
+            return true;
+        }
+        // Function bodies only run once called, by when later forms may have
+        // defined name.
+        self.function_depth > 0
+            && self
+                .declared
+                .as_ref()
+                .is_none_or(|declared| declared.contains(name))
+    }
+
+    // Resolves the children of a non-empty s-expression. Special forms which
+    // bind names or quote their arguments are handled here. Anything
+    // malformed is resolved like a function call and left for evaluation to
+    // report.
+    fn resolve_form(&mut self, children: &[ASTNode]) -> Vec<ASTNode> {
+        let head = self.resolve(&children[0]);
+        let args = &children[1..];
+        let mut resolved = vec![head];
+        let form = self.scopes.special_form(&children[0]);
+        if let Some(binding) = form.and_then(|form| Binding::of(form, args)) {
+            resolved.extend(self.resolve_binding(binding));
+            return resolved;
+        }
+        match (form, args) {
+            // Unquotes outside of a quasiquote are an error when evaluated, so
+            // there's no point reporting what's inside them.
+            (Some("quote" | "unquote" | "unquote-splicing"), _) => resolved.extend_from_slice(args),
+            (Some("quasiquote"), [template]) => resolved.push(self.resolve_template(template, 1)),
+            (Some("define"), [name @ ASTNode::Identifier { .. }, value]) => {
+                resolved.push(name.clone());
+                resolved.push(self.resolve(value));
+            }
+            (Some("set!"), [ASTNode::Identifier { name, span }, value]) => {
+                resolved.push(self.resolve_assigned(name, *span));
+                resolved.push(self.resolve(value));
+            }
+            (Some("try"), _) => {
+                for arg in args {
+                    let arg = self.resolve_try_part(arg);
+                    resolved.push(arg);
+                }
+            }
+            _ => resolved.extend(self.resolve_all(args)),
+        }
+        resolved
+    }
+
+    // Resolves the arguments of a form binding names.
+    fn resolve_binding(&mut self, binding: Binding) -> Vec<ASTNode> {
+        match binding {
+            Binding::Let {
+                id,
+                name,
+                value,
+                body,
+            } => {
+                let mut resolved = vec![id.clone(), self.resolve(value)];
+                resolved.extend(
+                    self.resolve_in_scope(vec![name.to_owned()], std::slice::from_ref(body)),
+                );
+                resolved
+            }
+            Binding::Function {
+                name,
+                params,
+                names,
+                body,
+            } => {
+                let mut resolved: Vec<ASTNode> = name.into_iter().cloned().collect();
+                resolved.push(params.clone());
+                resolved.extend(self.resolve_function_body(names, body));
+                resolved
+            }
+            Binding::Catch { id, name, handler } => {
+                let mut resolved = vec![id.clone()];
+                resolved.extend(self.resolve_in_scope(vec![name.to_owned()], handler));
+                resolved
+            }
+        }
+    }
+
+    // Resolves an argument to try. The name in a catch clause is bound for
+    // the rest of the clause.
+    fn resolve_try_part(&mut self, arg: &ASTNode) -> ASTNode {
+        let (head, (clause, rest)) = match (arg, try_clause(arg)) {
+            (ASTNode::SExpr { children, .. }, Some(clause)) => (children[0].clone(), clause),
+            _ => return self.resolve(arg),
+        };
+        let mut resolved = vec![head];
+        match Binding::of(clause, rest) {
+            Some(binding) => resolved.extend(self.resolve_binding(binding)),
+            None => resolved.extend(self.resolve_all(rest)),
+        }
+        ASTNode::SExpr {
+            children: resolved.into(),
+            span: arg.span(),
+        }
+    }
+
+    // Resolves the expressions in a quasiquote template which will be
+    // evaluated, leaving the rest as data. Mirrors expand_quasiquote.
+    fn resolve_template(&mut self, template: &ASTNode, depth: usize) -> ASTNode {
+        if let Some(inner) = form_argument(template, "unquote") {
+            let inner = if depth == 1 {
+                self.resolve(inner)
+            } else {
+                self.resolve_template(inner, depth - 1)
+            };
+            return with_argument(template, inner);
+        }
+        if let Some(inner) = form_argument(template, "quasiquote") {
+            let inner = self.resolve_template(inner, depth + 1);
+            return with_argument(template, inner);
+        }
+        match template {
+            ASTNode::SExpr { children, span } => {
+                let mut resolved = Vec::new();
+                for child in children.iter() {
+                    let child = match form_argument(child, "unquote-splicing") {
+                        Some(inner) if depth == 1 => with_argument(child, self.resolve(inner)),
+                        Some(inner) => {
+                            let inner = self.resolve_template(inner, depth - 1);
+                            with_argument(child, inner)
+                        }
+                        None => self.resolve_template(child, depth),
+                    };
+                    resolved.push(child);
+                }
+                ASTNode::SExpr {
+                    children: resolved.into(),
+                    span: *span,
+                }
+            }
+            _ => template.clone(),
+        }
+    }
+}
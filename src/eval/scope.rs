@@ -0,0 +1,186 @@
+use super::environment::Environment;
+use super::syntax_rules::original_name;
+use super::value::Value;
+use crate::parser::ASTNode;
+
+// Special forms defining a name given by their first argument.
+const DEFINING_FORMS: &[&str] = &["defun", "define"];
+
+// The local names visible at some point in a program, for the passes which
+// walk it before it runs: the expander, resolver and optimizer. Each scope
+// holds the names bound by an enclosing let, function or catch clause,
+// outermost first, matching the scopes the environment will have at runtime.
+pub struct Scopes<'a> {
+    env: &'a Environment,
+    names: Vec<Vec<String>>,
+}
+
+impl<'a> Scopes<'a> {
+    // Starts with the scopes code evaluated in env will be inside.
+    pub fn new(env: &'a Environment) -> Scopes<'a> {
+        Scopes {
+            env,
+            names: env.scope_names(),
+        }
+    }
+
+    pub fn push(&mut self, names: Vec<String>) {
+        self.names.push(names);
+    }
+
+    pub fn pop(&mut self) {
+        self.names.pop();
+    }
+
+    // Where name is bound locally, as the depth of its scope counting out
+    // from the innermost one and its index in that scope. See
+    // ASTNode::LocalRef.
+    pub fn position(&self, name: &str) -> Option<(usize, usize)> {
+        self.names
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                // Later bindings of the same name shadow earlier ones.
+                scope
+                    .iter()
+                    .rposition(|id| id == name)
+                    .map(|index| (depth, index))
+            })
+    }
+
+    pub fn is_local(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    // If head names a global which isn't shadowed, returns its name.
+    pub fn global_name<'n>(&self, head: &'n ASTNode) -> Option<&'n str> {
+        match head {
+            ASTNode::Identifier { name, .. } if !self.is_local(name) => Some(original_name(name)),
+            _ => None,
+        }
+    }
+
+    // If head names a special form, returns its name.
+    pub fn special_form<'n>(&self, head: &'n ASTNode) -> Option<&'n str> {
+        self.global_name(head)
+            .filter(|name| is_special_form(self.env, name))
+    }
+}
+
+pub fn is_special_form(env: &Environment, name: &str) -> bool {
+    matches!(env.get_global(name), Some(Value::LazyFunction(_)))
+}
+
+// A form which binds names for some of its arguments. Every pass which walks
+// code before it runs needs to agree on these.
+pub enum Binding<'a> {
+    // (let <Id> <value> <body>) binds id, named name, in body.
+    Let {
+        id: &'a ASTNode,
+        name: &'a str,
+        value: &'a ASTNode,
+        body: &'a ASTNode,
+    },
+    // (lambda (<Id>...) <body>...), or (defun <Id> (<Id>...) <body>...) with
+    // its name, binds names in body. Those are the parameters followed by
+    // the names body defines, in the order the call's scope holds them.
+    Function {
+        name: Option<&'a ASTNode>,
+        params: &'a ASTNode,
+        names: Vec<String>,
+        body: &'a [ASTNode],
+    },
+    // The clause (catch <Id> <handler>...) of a try binds id, named name, in
+    // handler.
+    Catch {
+        id: &'a ASTNode,
+        name: &'a str,
+        handler: &'a [ASTNode],
+    },
+}
+
+impl<'a> Binding<'a> {
+    // If form, the name of a special form or try clause, binds names when
+    // given args, returns what it binds. Malformed forms bind nothing.
+    pub fn of(form: &str, args: &'a [ASTNode]) -> Option<Binding<'a>> {
+        match (form, args) {
+            ("let", [id @ ASTNode::Identifier { name, .. }, value, body]) => Some(Binding::Let {
+                id,
+                name,
+                value,
+                body,
+            }),
+            ("lambda", [params, body @ ..]) => Binding::function(None, params, body),
+            ("defun", [name @ ASTNode::Identifier { .. }, params, body @ ..]) => {
+                Binding::function(Some(name), params, body)
+            }
+            ("catch", [id @ ASTNode::Identifier { name, .. }, handler @ ..]) => {
+                Some(Binding::Catch { id, name, handler })
+            }
+            _ => None,
+        }
+    }
+
+    fn function(
+        name: Option<&'a ASTNode>,
+        params: &'a ASTNode,
+        body: &'a [ASTNode],
+    ) -> Option<Binding<'a>> {
+        let mut names = param_names(params)?;
+        names.extend(internal_definitions(body));
+        Some(Binding::Function {
+            name,
+            params,
+            names,
+            body,
+        })
+    }
+}
+
+// If form is a definition, like (defun name ...), returns name. Works on
+// resolved code too.
+pub fn definition_name(form: &ASTNode) -> Option<String> {
+    let children = match form {
+        ASTNode::SExpr { children, .. } if children.len() > 1 => children,
+        _ => return None,
+    };
+    let head = match &children[0] {
+        ASTNode::Identifier { name, .. } => name.as_str(),
+        ASTNode::GlobalRef { name, .. } => name,
+        _ => return None,
+    };
+    match &children[1] {
+        ASTNode::Identifier { name, .. } if DEFINING_FORMS.contains(&head) => Some(name.to_owned()),
+        ASTNode::LocalRef { name, .. } if DEFINING_FORMS.contains(&head) => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+// The names defined by the definitions directly in a function's body. These
+// are local to each call, like letrec, so the body and every function it
+// defines can refer to them. They're bound alongside the function's
+// parameters, and reading one before its definition runs is an error.
+pub fn internal_definitions(body: &[ASTNode]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in body.iter().filter_map(definition_name) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+// Returns the names in a function's parameter list, if it is one.
+pub fn param_names(node: &ASTNode) -> Option<Vec<String>> {
+    match node {
+        ASTNode::SExpr { children, .. } => children
+            .iter()
+            .map(|child| match child {
+                ASTNode::Identifier { name, .. } => Some(name.to_owned()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
@@ -0,0 +1,338 @@
+use super::environment::Context;
+use super::error::RuntimeError;
+use super::value::{Callable, Value};
+use super::{Step, StepResult};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Separates an identifier a syntax-rules template introduced from the number
+// it was renamed with, e.g. tmp;3. Identifiers read from source can't contain
+// it, so renamed identifiers never clash with the user's.
+const RENAME_MARK: char = ';';
+
+const ELLIPSIS: &str = "...";
+
+pub fn is_renamed(name: &str) -> bool {
+    name.contains(RENAME_MARK)
+}
+
+// The name an identifier had in the template which introduced it. This is
+// what it refers to when nothing in the expansion binds it.
+pub fn original_name(name: &str) -> &str {
+    name.split(RENAME_MARK).next().unwrap_or(name)
+}
+
+// What a pattern variable matched. A variable followed by an ellipsis, or
+// inside something that is, matches any number of forms.
+#[derive(Clone)]
+enum Binding {
+    One(Value),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+// A macro defined by
+//
+//   (define-syntax <Id> (syntax-rules (<literal>...) (<pattern> <template>)...))
+//
+// A use of the macro is matched against each pattern in turn, ignoring the
+// macro's name, and replaced by the template of the first which matches. In
+// a pattern, _ matches anything, a literal matches only itself and any other
+// identifier is a pattern variable matching anything. A pattern followed by
+// ... matches any number of forms, and a template followed by ... is copied
+// once for each form its pattern variables matched.
+//
+// The template's other identifiers are renamed, so a binding it introduces
+// can't capture the user's variables. A renamed identifier which isn't bound
+// in the expansion refers to the global with its original name, whatever
+// the user has bound locally. See original_name.
+pub struct SyntaxRules {
+    name: Rc<str>,
+    literals: Vec<String>,
+    // Each pattern without the macro's name, and its template.
+    rules: Vec<(Value, Value)>,
+}
+
+impl SyntaxRules {
+    // Builds the macro name from spec, the (syntax-rules ...) form as data.
+    pub fn new(name: &str, spec: &Value) -> Result<SyntaxRules, RuntimeError> {
+        let (literals, rules) = match spec {
+            Value::List(items) => match &items[..] {
+                [Value::Symbol(head), Value::List(literals), rules @ ..]
+                    if original_name(head) == "syntax-rules" =>
+                {
+                    (literals, rules)
+                }
+                _ => return malformed_spec(spec),
+            },
+            _ => return malformed_spec(spec),
+        };
+        let literals = literals
+            .iter()
+            .map(|literal| match literal {
+                Value::Symbol(name) => Ok(original_name(name).to_owned()),
+                _ => RuntimeError::type_mismatch("syntax-rules", "symbol", literal),
+            })
+            .collect::<Result<_, _>>()?;
+        let mut parsed = Vec::new();
+        for rule in rules {
+            match rule {
+                Value::List(parts) => match &parts[..] {
+                    [Value::List(pattern), template] if !pattern.is_empty() => {
+                        let pattern = Value::List(pattern[1..].to_vec().into());
+                        check_pattern(&pattern)?;
+                        parsed.push((pattern, template.clone()));
+                    }
+                    _ => return malformed_rule(rule),
+                },
+                _ => return malformed_rule(rule),
+            }
+        }
+        Ok(SyntaxRules {
+            name: Rc::from(name),
+            literals,
+            rules: parsed,
+        })
+    }
+
+    fn matches(&self, pattern: &Value, form: &Value, bindings: &mut Bindings) -> bool {
+        match (pattern, form) {
+            (Value::Symbol(name), _) if name == "_" => true,
+            (Value::Symbol(name), _) if self.literals.contains(name) => {
+                matches!(form, Value::Symbol(s) if original_name(s) == name)
+            }
+            (Value::Symbol(name), _) => {
+                bindings.insert(name.to_owned(), Binding::One(form.clone()));
+                true
+            }
+            (Value::List(patterns), Value::List(forms)) => {
+                self.matches_list(patterns, forms, bindings)
+            }
+            (Value::List(_), _) => false,
+            _ => same_datum(pattern, form),
+        }
+    }
+
+    fn matches_list(&self, patterns: &[Value], forms: &[Value], bindings: &mut Bindings) -> bool {
+        let ellipsis = match patterns.iter().position(is_ellipsis) {
+            Some(ellipsis) => ellipsis,
+            None => {
+                return patterns.len() == forms.len()
+                    && patterns
+                        .iter()
+                        .zip(forms)
+                        .all(|(pattern, form)| self.matches(pattern, form, bindings))
+            }
+        };
+        // check_pattern ensures the ellipsis follows a pattern.
+        let (before, repeated, after) = (
+            &patterns[..ellipsis - 1],
+            &patterns[ellipsis - 1],
+            &patterns[ellipsis + 1..],
+        );
+        if forms.len() < before.len() + after.len() {
+            return false;
+        }
+        let rest = forms.len() - after.len();
+        if !self.matches_list(before, &forms[..before.len()], bindings)
+            || !self.matches_list(after, &forms[rest..], bindings)
+        {
+            return false;
+        }
+        let mut matched = Vec::new();
+        for form in &forms[before.len()..rest] {
+            let mut inner = Bindings::new();
+            if !self.matches(repeated, form, &mut inner) {
+                return false;
+            }
+            matched.push(inner);
+        }
+        for var in self.pattern_vars(repeated) {
+            let each = matched
+                .iter_mut()
+                .filter_map(|inner| inner.remove(&var))
+                .collect();
+            bindings.insert(var, Binding::Many(each));
+        }
+        true
+    }
+
+    fn pattern_vars(&self, pattern: &Value) -> Vec<String> {
+        match pattern {
+            Value::Symbol(name)
+                if name != "_" && name != ELLIPSIS && !self.literals.contains(name) =>
+            {
+                vec![name.to_owned()]
+            }
+            Value::List(patterns) => patterns.iter().flat_map(|p| self.pattern_vars(p)).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Callable for SyntaxRules {
+    fn invoke(&self, ctx: &mut Context, args: &[Value]) -> StepResult {
+        let form = Value::List(args.to_vec().into());
+        for (pattern, template) in &self.rules {
+            let mut bindings = Bindings::new();
+            if self.matches(pattern, &form, &mut bindings) {
+                let mut renames = HashMap::new();
+                let code = transcribe(ctx, template, &bindings, &mut renames)?;
+                return Ok(Step::Done(code));
+            }
+        }
+        RuntimeError::malformed(&format!(
+            "No pattern of {} matches its arguments, {}.",
+            self.name,
+            form.describe()
+        ))
+    }
+
+    fn name(&self) -> Rc<str> {
+        self.name.clone()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+// Fills in template with what the pattern variables matched, renaming every
+// other identifier. renames holds the names given so far, so each use of an
+// identifier in the template gets the same one.
+fn transcribe(
+    ctx: &mut Context,
+    template: &Value,
+    bindings: &Bindings,
+    renames: &mut HashMap<String, String>,
+) -> Result<Value, RuntimeError> {
+    match template {
+        Value::Symbol(name) => match bindings.get(name) {
+            Some(Binding::One(form)) => Ok(form.clone()),
+            Some(Binding::Many(_)) => RuntimeError::malformed(&format!(
+                "Pattern variable {} must be followed by {} in the template.",
+                name, ELLIPSIS
+            )),
+            None => {
+                let renamed = renames.entry(name.to_owned()).or_insert_with(|| {
+                    ctx.renamed += 1;
+                    format!("{}{}{}", name, RENAME_MARK, ctx.renamed)
+                });
+                Ok(Value::Symbol(renamed.clone()))
+            }
+        },
+        Value::List(templates) => {
+            let mut transcribed = Vec::new();
+            let mut i = 0;
+            while i < templates.len() {
+                let sub = &templates[i];
+                if !templates.get(i + 1).is_some_and(is_ellipsis) {
+                    transcribed.push(transcribe(ctx, sub, bindings, renames)?);
+                    i += 1;
+                    continue;
+                }
+                for each in repetitions(sub, bindings)? {
+                    transcribed.push(transcribe(ctx, sub, &each, renames)?);
+                }
+                i += 2;
+            }
+            Ok(Value::List(transcribed.into()))
+        }
+        _ => Ok(template.clone()),
+    }
+}
+
+// The bindings to transcribe template with for each copy of it made by a
+// following ellipsis. Each binds the repeated variables template uses to
+// the next form they matched.
+fn repetitions(template: &Value, bindings: &Bindings) -> Result<Vec<Bindings>, RuntimeError> {
+    let mut repeated = Vec::new();
+    template_symbols(template, &mut |name| {
+        if let Some(Binding::Many(each)) = bindings.get(name) {
+            repeated.push((name.to_owned(), each.clone()));
+        }
+    });
+    let count = match repeated.first() {
+        Some((_, each)) => each.len(),
+        None => {
+            return RuntimeError::malformed(&format!(
+                "Template followed by {} has no pattern variables to repeat.",
+                ELLIPSIS
+            ))
+        }
+    };
+    if repeated.iter().any(|(_, each)| each.len() != count) {
+        return RuntimeError::malformed(&format!(
+            "Pattern variables repeated by the same {} matched different numbers of forms.",
+            ELLIPSIS
+        ));
+    }
+    Ok((0..count)
+        .map(|i| {
+            let mut each = bindings.clone();
+            for (name, forms) in &repeated {
+                each.insert(name.clone(), forms[i].clone());
+            }
+            each
+        })
+        .collect())
+}
+
+fn template_symbols(template: &Value, f: &mut impl FnMut(&str)) {
+    match template {
+        Value::Symbol(name) => f(name),
+        Value::List(templates) => {
+            for template in templates {
+                template_symbols(template, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Checks every ellipsis in pattern follows something to repeat, and that no
+// list has more than one.
+fn check_pattern(pattern: &Value) -> Result<(), RuntimeError> {
+    if let Value::List(patterns) = pattern {
+        let ellipses: Vec<usize> = patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| is_ellipsis(p))
+            .map(|(i, _)| i)
+            .collect();
+        if ellipses.len() > 1 || ellipses.first() == Some(&0) {
+            return RuntimeError::malformed(&format!(
+                "A syntax-rules pattern list can have one {} after an element. Found {}.",
+                ELLIPSIS,
+                pattern.describe()
+            ));
+        }
+        for p in patterns {
+            check_pattern(p)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_ellipsis(value: &Value) -> bool {
+    matches!(value, Value::Symbol(name) if name == ELLIPSIS)
+}
+
+fn same_datum(a: &Value, b: &Value) -> bool {
+    a.type_name() == b.type_name() && a.runtime_to_str() == b.runtime_to_str()
+}
+
+fn malformed_spec<T>(spec: &Value) -> Result<T, RuntimeError> {
+    RuntimeError::malformed(&format!(
+        "Expected (syntax-rules (<literal>...) (<pattern> <template>)...). Found {}.",
+        spec.describe()
+    ))
+}
+
+fn malformed_rule<T>(rule: &Value) -> Result<T, RuntimeError> {
+    RuntimeError::malformed(&format!(
+        "Expected a syntax-rules rule (<pattern> <template>). Found {}.",
+        rule.describe()
+    ))
+}
@@ -1,76 +1,240 @@
 use crate::eval::environment::{Context, Environment};
-use crate::eval::error::RuntimeError;
+use crate::eval::error::{ErrorKind, RuntimeError};
+use crate::eval::StepResult;
 use crate::lexer::Token;
 use crate::parser::ASTNode;
+use crate::span::Span;
 use std::rc::Rc;
 
 // Trait that defines a "normal" function call. Arguments to the function are
 // evaluated prior to invoking the actual function. Think things like '<',
 // 'write', etc.
 pub trait Callable {
-    fn invoke(
-        &self,
-        env: &Environment,
-        ctx: &mut Context,
-        args: &[Value],
-    ) -> Result<Value, RuntimeError>;
+    fn invoke(&self, ctx: &mut Context, args: &[Value]) -> StepResult;
+
+    // What to call the function in error messages and backtraces.
+    fn name(&self) -> Rc<str>;
+
+    // Lets callers which know about a particular kind of closure, like the
+    // bytecode VM, recognise their own.
+    fn as_any(&self) -> &dyn std::any::Any;
 }
 
 #[derive(Clone)]
 pub enum Value {
     Int(i64),
+    Float(f64),
     Boolean(bool),
+    Char(char),
     Str(String),
+    // An identifier treated as data, e.g. the result of evaluating 'x.
+    Symbol(String),
     Function(fn(&Environment, &mut Context, &[Value]) -> Result<Value, RuntimeError>),
-    LazyFunction(fn(&Environment, &mut Context, &[ASTNode]) -> Result<Value, RuntimeError>),
-    EnvMutatingFunction(fn(&mut Environment, &[ASTNode]) -> Result<Value, RuntimeError>),
+    // A special form, which is passed its arguments unevaluated.
+    LazyFunction(fn(&Environment, &mut Context, &[ASTNode]) -> StepResult),
     Closure(Rc<dyn Callable>),
-    List(Vec<Value>),
+    // A function from code to code, defined by defmacro or define-syntax.
+    // Uses of it are replaced by the code it returns before evaluation. See
+    // expander.
+    Macro(Rc<dyn Callable>),
+    List(List),
+    // An error caught by try, which can be inspected or thrown again.
+    Error(Rc<RuntimeError>),
     Unit,
 }
 
+// The items of a list. Lists are shared rather than copied, so building a
+// list out of others, or cloning one, costs the same however long or deeply
+// nested they are.
+#[derive(Clone, Default)]
+pub struct List(Rc<Vec<Value>>);
+
+impl List {
+    // Returns the items, copying them only if another value shares them.
+    pub fn into_vec(mut self) -> Vec<Value> {
+        match Rc::get_mut(&mut self.0) {
+            Some(items) => std::mem::take(items),
+            None => self.0.to_vec(),
+        }
+    }
+}
+
+impl From<Vec<Value>> for List {
+    fn from(items: Vec<Value>) -> List {
+        List(Rc::new(items))
+    }
+}
+
+impl<'a> IntoIterator for &'a List {
+    type Item = &'a Value;
+    type IntoIter = std::slice::Iter<'a, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl std::ops::Deref for List {
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        &self.0
+    }
+}
+
+// Dropping a list drops its items, so dropping a deeply nested list would
+// recurse once for each level and could overflow the stack. Instead, lists
+// nothing else shares are emptied into pending here, and their items
+// dropped in a loop.
+impl Drop for List {
+    fn drop(&mut self) {
+        let mut pending = match Rc::get_mut(&mut self.0) {
+            Some(items) => vec![std::mem::take(items)],
+            None => return,
+        };
+        while let Some(items) = pending.pop() {
+            for mut item in items {
+                if let Value::List(List(inner)) = &mut item {
+                    if let Some(inner) = Rc::get_mut(inner) {
+                        pending.push(std::mem::take(inner));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
 impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         let mut dbs = f.debug_struct("value::Value");
         match self {
             Value::Int(i) => dbs.field("i64", i),
+            Value::Float(x) => dbs.field("f64", x),
             Value::Boolean(b) => dbs.field("bool", b),
+            Value::Char(c) => dbs.field("char", c),
             Value::Str(s) => dbs.field("String", s),
+            Value::Symbol(s) => dbs.field("Symbol", s),
             Value::List(lst) => dbs.field("list", lst),
             Value::Function(_) => dbs.field("Function", &"<No Name>"),
             Value::Closure(_) => dbs.field("Closure", &"<No Name>"),
+            Value::Macro(m) => dbs.field("Macro", &m.name()),
             Value::LazyFunction(_) => dbs.field("LazyFunction", &"<No Name>"),
-            Value::EnvMutatingFunction(_) => dbs.field("EnvMutatingFunction", &"<No Name>"),
+            Value::Error(error) => dbs.field("Error", &error.message()),
             Value::Unit => dbs.field("Unit", &""),
         }
         .finish()
     }
 }
 
+// Prints the list lst. Nested lists are printed in a loop rather than by
+// recursing, so deeply nested lists don't overflow the stack.
 fn list_to_str(lst: &[Value]) -> Result<String, String> {
-    let mut strs = Vec::new();
-    for v in lst {
-        strs.push(v.runtime_to_str()?);
+    let mut printed = String::from("(");
+    // The rest of each list being printed, innermost last, along with
+    // whether any of its items have been printed yet.
+    let mut pending = vec![(lst.iter(), false)];
+    while let Some((items, started)) = pending.last_mut() {
+        let item = match items.next() {
+            Some(item) => item,
+            None => {
+                printed.push(')');
+                pending.pop();
+                continue;
+            }
+        };
+        if std::mem::replace(started, true) {
+            printed.push(' ');
+        }
+        match item {
+            Value::List(inner) => {
+                printed.push('(');
+                pending.push((inner.iter(), false));
+            }
+            _ => printed.push_str(&item.runtime_to_str()?),
+        }
     }
-    Ok(format!("({})", strs.join(" ")))
+    Ok(printed)
 }
 
 impl Value {
     pub fn parse(token: &Token) -> Result<Value, RuntimeError> {
         match token {
             Token::IntLiteral(v) => Ok(Value::Int(v.to_owned())),
+            Token::RadixIntLiteral { value, .. } => Ok(Value::Int(value.to_owned())),
+            Token::FloatLiteral(x) => Ok(Value::Float(x.to_owned())),
+            Token::BoolLiteral(b) => Ok(Value::Boolean(*b)),
+            Token::NilLiteral => Ok(Value::List(List::default())),
+            Token::CharLiteral(c) => Ok(Value::Char(*c)),
             Token::StringLiteral(s) => Ok(Value::Str(s.to_owned())),
-            _ => RuntimeError::new(&format!("Could not convert token {:?} to Value.", token)),
+            _ => RuntimeError::new(ErrorKind::Other {
+                message: format!("Could not convert token {:?} to Value.", token),
+            }),
+        }
+    }
+
+    // Converts code into the data it represents: identifiers become symbols
+    // and s-expressions become lists.
+    pub fn from_ast(node: &ASTNode) -> Result<Value, RuntimeError> {
+        match node {
+            ASTNode::Terminal { token, .. } => Value::parse(token),
+            ASTNode::Identifier { name, .. } => Ok(Value::Symbol(name.to_owned())),
+            ASTNode::LocalRef { name, .. } | ASTNode::GlobalRef { name, .. } => {
+                Ok(Value::Symbol(name.to_string()))
+            }
+            ASTNode::SExpr { children, .. } => {
+                let mut lst = Vec::new();
+                for child in children.iter() {
+                    lst.push(Value::from_ast(child)?);
+                }
+                Ok(Value::List(lst.into()))
+            }
+            ASTNode::Program { .. } => RuntimeError::new(ErrorKind::Other {
+                message: "Cannot quote a whole program.".to_owned(),
+            }),
         }
     }
 
+    // The inverse of from_ast. Converts data back into code which can be
+    // evaluated. Since the data has no source location, every node is given
+    // span.
+    pub fn to_ast(&self, span: Span) -> Result<ASTNode, RuntimeError> {
+        let token = match self {
+            Value::Int(i) => Token::IntLiteral(*i),
+            Value::Float(x) => Token::FloatLiteral(*x),
+            Value::Boolean(b) => Token::BoolLiteral(*b),
+            Value::Char(c) => Token::CharLiteral(*c),
+            Value::Str(s) => Token::StringLiteral(s.to_owned()),
+            Value::Symbol(name) => {
+                return Ok(ASTNode::Identifier {
+                    name: name.to_owned(),
+                    span,
+                })
+            }
+            Value::List(lst) => {
+                let mut children = Vec::new();
+                for v in lst {
+                    children.push(v.to_ast(span)?);
+                }
+                return Ok(ASTNode::SExpr {
+                    children: children.into(),
+                    span,
+                });
+            }
+            _ => return RuntimeError::type_mismatch("eval", "data representing code", self),
+        };
+        Ok(ASTNode::Terminal { token, span })
+    }
+
     pub fn is_callable(&self) -> bool {
         matches!(
             self,
-            Value::Closure(_)
-                | Value::Function(_)
-                | Value::LazyFunction(_)
-                | Value::EnvMutatingFunction(_)
+            Value::Closure(_) | Value::Function(_) | Value::LazyFunction(_)
         )
     }
 
@@ -80,10 +244,43 @@ impl Value {
     pub fn runtime_to_str(&self) -> Result<String, String> {
         match self {
             Value::Int(i) => Ok(i.to_string()),
+            // Debug formatting always includes a decimal point or exponent,
+            // so floats can't be mistaken for ints when printed.
+            Value::Float(x) => Ok(format!("{:?}", x)),
             Value::Boolean(b) => Ok(b.to_string()),
+            Value::Char(c) => Ok(c.to_string()),
             Value::Str(s) => Ok(s.to_string()),
+            Value::Symbol(s) => Ok(s.to_string()),
             Value::List(lst) => Ok(list_to_str(lst)?),
             _ => Err("".to_string()),
         }
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "bool",
+            Value::Char(_) => "char",
+            Value::Str(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Function(_) | Value::Closure(_) => "function",
+            Value::LazyFunction(_) => "special form",
+            Value::Macro(_) => "macro",
+            Value::List(_) => "list",
+            Value::Error(_) => "error",
+            Value::Unit => "unit",
+        }
+    }
+
+    // Describes the value for error messages, e.g. 'string "abc"'.
+    pub fn describe(&self) -> String {
+        match (self, self.runtime_to_str()) {
+            (Value::Str(s), _) => format!("string {:?}", s),
+            (Value::Error(error), _) => format!("error {:?}", error.message()),
+            (Value::Unit, _) => self.type_name().to_owned(),
+            (_, Ok(s)) => format!("{} {}", self.type_name(), s),
+            (_, Err(_)) => self.type_name().to_owned(),
+        }
+    }
 }
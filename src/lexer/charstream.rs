@@ -1,13 +1,32 @@
+use crate::span::Position;
 use core::iter::Iterator;
+use std::collections::VecDeque;
+use std::io::BufRead;
 
 pub trait CharStream {
     fn advance(&mut self) -> Option<char>;
     fn peek(&mut self) -> Option<char>;
+
+    // Looks one character past peek. Needed to recognize two character
+    // prefixes like '#|' without consuming the '#'.
+    fn peek_next(&mut self) -> Option<char>;
+
+    // The position of the character that the next call to advance will
+    // return.
+    fn current_position(&self) -> Position;
+
+    // Streams which can fail, like ones reading from a file, report the end
+    // of input when they hit an error. This returns the error, if any, so it
+    // can be distinguished from a real end of input.
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
 }
 
 pub struct StaticCharStream {
     buffer: Vec<char>,
     curr: usize,
+    position: Position,
 }
 
 impl StaticCharStream {
@@ -15,6 +34,7 @@ impl StaticCharStream {
         StaticCharStream {
             buffer: input.chars().collect(),
             curr: 0,
+            position: Position::start(),
         }
     }
 }
@@ -26,6 +46,7 @@ impl CharStream for StaticCharStream {
         } else {
             let c = self.buffer[self.curr];
             self.curr += 1;
+            self.position.advance(c);
             Some(c)
         }
     }
@@ -37,6 +58,153 @@ impl CharStream for StaticCharStream {
             Some(self.buffer[self.curr])
         }
     }
+
+    fn peek_next(&mut self) -> Option<char> {
+        self.buffer.get(self.curr + 1).copied()
+    }
+
+    fn current_position(&self) -> Position {
+        self.position
+    }
+}
+
+// Reads characters from any BufRead, decoding UTF-8 only as characters are
+// needed. Unlike StaticCharStream this never holds more than a couple of
+// characters of the input, so it works on pipes and sockets where the input
+// arrives over time.
+pub struct ReaderCharStream<R: BufRead> {
+    reader: R,
+    lookahead: VecDeque<char>,
+    position: Position,
+    error: Option<String>,
+}
+
+impl<R: BufRead> ReaderCharStream<R> {
+    pub fn new(reader: R) -> ReaderCharStream<R> {
+        ReaderCharStream {
+            reader,
+            lookahead: VecDeque::new(),
+            position: Position::start(),
+            error: None,
+        }
+    }
+
+    // Reads characters into the lookahead until it has at least n of them or
+    // the input runs out.
+    fn fill_lookahead(&mut self, n: usize) {
+        while self.lookahead.len() < n && self.error.is_none() {
+            match self.decode_char() {
+                Ok(Some(c)) => self.lookahead.push_back(c),
+                Ok(None) => return,
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, String> {
+        let buf = self.reader.fill_buf().map_err(|e| e.to_string())?;
+        let byte = buf.first().copied();
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    // Reads the bytes of a single UTF-8 encoded character.
+    fn decode_char(&mut self) -> Result<Option<char>, String> {
+        let first = match self.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let len = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Err(format!("Invalid UTF-8 start byte 0x{:x} in input.", first)),
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.read_byte()? {
+                Some(byte) => bytes.push(byte),
+                None => return Err("Input ended in the middle of a UTF-8 character.".to_string()),
+            }
+        }
+        let decoded = std::str::from_utf8(&bytes).map_err(|e| e.to_string())?;
+        Ok(decoded.chars().next())
+    }
+}
+
+impl<R: BufRead> CharStream for ReaderCharStream<R> {
+    fn advance(&mut self) -> Option<char> {
+        self.fill_lookahead(1);
+        let c = self.lookahead.pop_front()?;
+        self.position.advance(c);
+        Some(c)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.fill_lookahead(1);
+        self.lookahead.front().copied()
+    }
+
+    fn peek_next(&mut self) -> Option<char> {
+        self.fill_lookahead(2);
+        self.lookahead.get(1).copied()
+    }
+
+    fn current_position(&self) -> Position {
+        self.position
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+}
+
+// Wraps another CharStream and keeps a copy of every character read through
+// it, so the exact source text of a token can be recovered.
+pub struct RecordingCharStream {
+    inner: Box<dyn CharStream>,
+    recorded: String,
+}
+
+impl RecordingCharStream {
+    pub fn new(inner: Box<dyn CharStream>) -> RecordingCharStream {
+        RecordingCharStream {
+            inner,
+            recorded: String::new(),
+        }
+    }
+
+    // Returns everything read since the last call.
+    pub fn take_recorded(&mut self) -> String {
+        std::mem::take(&mut self.recorded)
+    }
+}
+
+impl CharStream for RecordingCharStream {
+    fn advance(&mut self) -> Option<char> {
+        let c = self.inner.advance()?;
+        self.recorded.push(c);
+        Some(c)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.inner.peek()
+    }
+
+    fn peek_next(&mut self) -> Option<char> {
+        self.inner.peek_next()
+    }
+
+    fn current_position(&self) -> Position {
+        self.inner.current_position()
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.inner.take_error()
+    }
 }
 
 impl core::iter::Iterator for dyn CharStream {
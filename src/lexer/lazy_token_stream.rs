@@ -1,26 +1,30 @@
 use crate::lexer::charstream::CharStream;
+use crate::lexer::charstream::ReaderCharStream;
+use crate::lexer::charstream::RecordingCharStream;
 use crate::lexer::charstream::StaticCharStream;
+use crate::lexer::SpannedToken;
 use crate::lexer::Token;
 use crate::lexer::TokenError;
 use crate::lexer::TokenStream;
+use crate::lexer::{Trivia, TriviaKind};
+use crate::span::{Position, Span};
+use std::io::BufRead;
 
 pub struct LazyTokenStream {
-    char_stream: Box<dyn CharStream>,
-    next_token: Option<Token>,
+    char_stream: RecordingCharStream,
+    next_token: Option<SpannedToken>,
+    trailing_trivia: Vec<Trivia>,
 }
 
 impl TokenStream for LazyTokenStream {
-    fn advance(&mut self) -> Result<Option<Token>, TokenError> {
-        if self.next_token.is_some() {
-            let next = self.next_token.clone();
-            self.next_token = self.consume_token_from_input()?;
-            Ok(next)
-        } else {
-            Ok(Option::None)
-        }
+    // Only lexes the token being taken. Reading ahead to the one after would
+    // wait for more input, and might fail, before the caller could use it.
+    fn advance(&mut self) -> Result<Option<SpannedToken>, TokenError> {
+        self.peek()?;
+        Ok(self.next_token.take())
     }
 
-    fn peek(&mut self) -> Result<Option<Token>, TokenError> {
+    fn peek(&mut self) -> Result<Option<SpannedToken>, TokenError> {
         if self.next_token.is_some() {
             Ok(self.next_token.clone())
         } else {
@@ -28,65 +32,497 @@ impl TokenStream for LazyTokenStream {
             Ok(self.next_token.clone())
         }
     }
+
+    fn position(&self) -> Position {
+        self.char_stream.current_position()
+    }
+
+    fn trailing_trivia(&self) -> &[Trivia] {
+        &self.trailing_trivia
+    }
 }
 
+// Whether c can appear in an identifier or number. Identifiers may contain
+// digits, just not start with something that looks like a number.
 fn is_identifier_char(c: char) -> bool {
-    !c.is_whitespace() && !c.is_ascii_digit() && c != '(' && c != ')'
+    !c.is_whitespace() && !matches!(c, '(' | ')' | ';' | '"' | '\'' | '`' | ',')
+}
+
+// Whether an atom should be read as a number rather than an identifier. This
+// is the case when it starts with a digit, optionally after a sign and/or a
+// decimal point, so '-5', '.5' and '+.5' are numbers while '-', '...' and
+// '-x' are identifiers.
+fn looks_numeric(text: &str) -> bool {
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    let unsigned = unsigned.strip_prefix('.').unwrap_or(unsigned);
+    unsigned.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// Whether text matches digits ('.' digits)? ([eE] [+-]? digits)? with at
+// least one digit before the exponent.
+fn is_float_syntax(text: &str) -> bool {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (mantissa, ""),
+    };
+    let all_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    let mantissa_ok =
+        all_digits(whole) && all_digits(fraction) && !(whole.is_empty() && fraction.is_empty());
+    let exponent_ok = exponent.is_none_or(|e| {
+        let digits = e.strip_prefix(['+', '-']).unwrap_or(e);
+        !digits.is_empty() && all_digits(digits)
+    });
+    mantissa_ok && exponent_ok
+}
+
+// Parses text, which may carry a sign, as an integer in the given radix.
+fn parse_integer(text: &str, radix: u32) -> Result<i64, String> {
+    i64::from_str_radix(text, radix).map_err(|e| match e.kind() {
+        std::num::IntErrorKind::PosOverflow | std::num::IntErrorKind::NegOverflow => {
+            format!("Integer literal '{}' does not fit in 64 bits.", text)
+        }
+        _ => format!("Invalid base {} integer literal '{}'.", radix, text),
+    })
+}
+
+// Parses a decimal integer or floating point literal.
+fn parse_decimal(text: &str) -> Result<Token, String> {
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if unsigned.chars().all(|c| c.is_ascii_digit()) {
+        parse_integer(text, 10).map(Token::IntLiteral)
+    } else if is_float_syntax(unsigned) {
+        let value = text
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid float literal '{}': {}", text, e))?;
+        if value.is_finite() {
+            Ok(Token::FloatLiteral(value))
+        } else {
+            Err(format!("Float literal '{}' is out of range.", text))
+        }
+    } else {
+        Err(format!("Invalid numeric literal '{}'.", text))
+    }
+}
+
+// The character a #\ literal refers to by name, e.g. #\newline.
+fn named_char(name: &str) -> Option<char> {
+    match name {
+        "newline" | "linefeed" => Some('\n'),
+        "space" => Some(' '),
+        "tab" => Some('\t'),
+        "return" => Some('\r'),
+        "nul" | "null" => Some('\0'),
+        "alarm" => Some('\x07'),
+        "backspace" => Some('\x08'),
+        "escape" => Some('\x1b'),
+        "delete" => Some('\x7f'),
+        _ => None,
+    }
+}
+
+// The radix named by the letter following '#' in literals like #xFF.
+fn radix_of(prefix: char) -> Option<u32> {
+    match prefix.to_ascii_lowercase() {
+        'b' => Some(2),
+        'o' => Some(8),
+        'd' => Some(10),
+        'x' => Some(16),
+        _ => None,
+    }
 }
 
 impl LazyTokenStream {
     pub fn new_from_string(s: &str) -> LazyTokenStream {
+        LazyTokenStream::new(Box::new(StaticCharStream::new(s)))
+    }
+
+    pub fn new_from_reader<R: BufRead + 'static>(reader: R) -> LazyTokenStream {
+        LazyTokenStream::new(Box::new(ReaderCharStream::new(reader)))
+    }
+
+    fn new(char_stream: Box<dyn CharStream>) -> LazyTokenStream {
         LazyTokenStream {
-            char_stream: Box::new(StaticCharStream::new(s)),
+            char_stream: RecordingCharStream::new(char_stream),
             next_token: Option::None,
+            trailing_trivia: Vec::new(),
         }
     }
 
-    fn consume_token_from_input(&mut self) -> Result<Option<Token>, TokenError> {
-        self.consume_whitespace();
-        if self.char_stream.peek().is_none() {
-            return Ok(Option::None);
+    fn consume_token_from_input(&mut self) -> Result<Option<SpannedToken>, TokenError> {
+        let start = self.char_stream.current_position();
+        let token = self.consume_spanned_token();
+        // A failure to read the input looks like the end of input to the
+        // rest of the lexer, so it takes priority over whatever that caused.
+        if let Some(message) = self.char_stream.take_error() {
+            return Err(TokenError::new(message, self.span_from(start)));
         }
+        token
+    }
+
+    fn consume_spanned_token(&mut self) -> Result<Option<SpannedToken>, TokenError> {
+        let leading_trivia = self.consume_trivia()?;
+        let start = self.char_stream.current_position();
+        self.char_stream.take_recorded();
+        match self.consume_token(start)? {
+            Some(token) => Ok(Some(SpannedToken {
+                token,
+                span: self.span_from(start),
+                text: self.char_stream.take_recorded(),
+                leading_trivia,
+            })),
+            None => {
+                // Once at the end of input this is called again on every
+                // peek, so only the first call will find any trivia.
+                self.trailing_trivia.extend(leading_trivia);
+                Ok(None)
+            }
+        }
+    }
+
+    fn consume_token(&mut self, start: Position) -> Result<Option<Token>, TokenError> {
         let curr_char = if let Some(c) = self.char_stream.peek() {
-            Ok(c)
+            c
         } else {
-            Err(TokenError::new("Reached end of input.".to_string()))
-        }?;
+            return Ok(Option::None);
+        };
         if curr_char == '(' {
             self.char_stream.advance();
             Ok(Some(Token::OpenParen))
         } else if curr_char == ')' {
             self.char_stream.advance();
             Ok(Some(Token::CloseParen))
+        } else if curr_char == '\'' {
+            self.char_stream.advance();
+            Ok(Some(Token::Quote))
+        } else if curr_char == '`' {
+            self.char_stream.advance();
+            Ok(Some(Token::Quasiquote))
+        } else if curr_char == ',' {
+            self.char_stream.advance();
+            if self.char_stream.peek() == Some('@') {
+                self.char_stream.advance();
+                Ok(Some(Token::UnquoteSplicing))
+            } else {
+                Ok(Some(Token::Unquote))
+            }
+        } else if curr_char == '#' {
+            self.consume_hash_syntax(start).map(Some)
         } else if curr_char == '"' {
-            self.consume_string().map(Some)
-        } else if curr_char.is_ascii_digit() {
-            self.consume_integer().map(Some)
+            self.consume_string(start).map(Some)
         } else {
-            self.consume_identifier().map(Some)
+            self.consume_atom(start).map(Some)
         }
     }
 
-    fn consume_whitespace(&mut self) {
-        while self.char_stream.peek().map_or(false, |c| c.is_whitespace()) {
-            self.char_stream.advance();
+    // The span from start up to the current position in the input.
+    fn span_from(&self, start: Position) -> Span {
+        Span::new(start, self.char_stream.current_position())
+    }
+
+    // Consumes all whitespace and comments up to the next token.
+    fn consume_trivia(&mut self) -> Result<Vec<Trivia>, TokenError> {
+        let mut trivia = Vec::new();
+        loop {
+            let start = self.char_stream.current_position();
+            let (kind, text) = match self.char_stream.peek() {
+                Some(c) if c.is_whitespace() => (
+                    TriviaKind::Whitespace,
+                    self.consume_while(|c| c.is_whitespace())?,
+                ),
+                Some(';') => (TriviaKind::LineComment, self.consume_while(|c| c != '\n')?),
+                Some('#') if self.char_stream.peek_next() == Some('|') => {
+                    (TriviaKind::BlockComment, self.consume_block_comment(start)?)
+                }
+                _ => return Ok(trivia),
+            };
+            trivia.push(Trivia {
+                kind,
+                text,
+                span: self.span_from(start),
+            });
         }
     }
-    fn consume_string(&mut self) -> Result<Token, TokenError> {
+
+    // Consumes a '#| ... |#' comment, including any comments nested inside.
+    fn consume_block_comment(&mut self, start: Position) -> Result<String, TokenError> {
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            let c = self.char_stream.advance().ok_or_else(|| {
+                TokenError::end_of_input(
+                    "Reached end of input inside a block comment.".to_string(),
+                    self.span_from(start),
+                )
+            })?;
+            text.push(c);
+            if c == '#' && self.char_stream.peek() == Some('|') {
+                text.extend(self.char_stream.advance());
+                depth += 1;
+            } else if c == '|' && self.char_stream.peek() == Some('#') {
+                text.extend(self.char_stream.advance());
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(text);
+                }
+            }
+        }
+    }
+
+    // Consumes a token starting with '#'.
+    fn consume_hash_syntax(&mut self, start: Position) -> Result<Token, TokenError> {
         self.char_stream.advance();
-        let literal = self.consume_while(|c| c != '"')?;
+        match self.char_stream.peek() {
+            Some(';') => {
+                self.char_stream.advance();
+                Ok(Token::DatumComment)
+            }
+            Some('#') | Some('"') => self.consume_raw_string(start),
+            Some('\\') => {
+                self.char_stream.advance();
+                self.consume_char(start)
+            }
+            _ => {
+                let text = self.consume_while(is_identifier_char)?;
+                match text.as_str() {
+                    "t" | "true" => Ok(Token::BoolLiteral(true)),
+                    "f" | "false" => Ok(Token::BoolLiteral(false)),
+                    _ => match self.radix_literal(&text, start)? {
+                        Some(token) => Ok(token),
+                        None => Ok(Token::Id(format!("#{}", text))),
+                    },
+                }
+            }
+        }
+    }
+
+    // Consumes the part of a character literal following the '#\\'. This is
+    // either a single character, which may be a delimiter like '(' or ' ', a
+    // name like 'newline' or a hex code like 'x41'.
+    fn consume_char(&mut self, start: Position) -> Result<Token, TokenError> {
+        let first = self.char_stream.advance().ok_or_else(|| {
+            TokenError::end_of_input(
+                "Reached end of input inside a character literal.".to_string(),
+                self.span_from(start),
+            )
+        })?;
+        // A delimiter like ' ' or '(' always ends the literal, even when a
+        // name could follow it.
+        if !is_identifier_char(first) {
+            return Ok(Token::CharLiteral(first));
+        }
+        let rest = self.consume_while(is_identifier_char)?;
+        if rest.is_empty() {
+            return Ok(Token::CharLiteral(first));
+        }
+        let name = format!("{}{}", first, rest);
+        let hex_code = if first == 'x' || first == 'U' || first == 'u' {
+            u32::from_str_radix(&rest, 16).ok().and_then(char::from_u32)
+        } else {
+            None
+        };
+        named_char(&name)
+            .or(hex_code)
+            .map(Token::CharLiteral)
+            .ok_or_else(|| {
+                TokenError::new(
+                    format!("Unknown character name '#\\{}'.", name),
+                    self.span_from(start),
+                )
+            })
+    }
+
+    // Interprets the text following a '#' as a radix prefixed integer such
+    // as #xFF or #b-101. Returns None if it doesn't look like one.
+    fn radix_literal(&self, text: &str, start: Position) -> Result<Option<Token>, TokenError> {
+        let mut chars = text.chars();
+        let radix = match chars.next().and_then(radix_of) {
+            Some(radix) => radix,
+            None => return Ok(None),
+        };
+        let digits = chars.as_str();
+        let unsigned = digits.strip_prefix(['+', '-']).unwrap_or(digits);
+        if !unsigned.starts_with(|c: char| c.is_digit(radix)) {
+            return Ok(None);
+        }
+        let value =
+            parse_integer(digits, radix).map_err(|e| TokenError::new(e, self.span_from(start)))?;
+        Ok(Some(Token::RadixIntLiteral { value, radix }))
+    }
+
+    fn consume_string(&mut self, start: Position) -> Result<Token, TokenError> {
         self.char_stream.advance();
-        Ok(Token::StringLiteral(literal))
+        let mut literal = String::new();
+        // A bad escape is only reported once the whole string has been read,
+        // so the lexer can carry on after the string.
+        let mut escape_error = None;
+        loop {
+            let escape_start = self.char_stream.current_position();
+            match self.char_stream.advance() {
+                Some('"') => {
+                    return match escape_error {
+                        Some(e) => Err(e),
+                        None => Ok(Token::StringLiteral(literal)),
+                    }
+                }
+                Some('\\') => match self.consume_escape(escape_start) {
+                    Ok(c) => literal.push(c),
+                    Err(e) => {
+                        escape_error.get_or_insert(e);
+                    }
+                },
+                Some(c) => literal.push(c),
+                None => {
+                    return Err(TokenError::end_of_input(
+                        "Reached end of input inside a string literal.".to_string(),
+                        self.span_from(start),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Consumes the part of an escape sequence following the '\\' and returns
+    // the character it stands for.
+    fn consume_escape(&mut self, start: Position) -> Result<char, TokenError> {
+        let escaped = match self.char_stream.advance() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('x') => {
+                // Exactly two hex digits, e.g. \x41. Anything else, like the
+                // closing quote, is left unread.
+                let mut digits = String::new();
+                for _ in 0..2 {
+                    match self.char_stream.peek() {
+                        Some(c) if c.is_ascii_hexdigit() => {
+                            digits.extend(self.char_stream.advance())
+                        }
+                        _ => {
+                            return Err(TokenError::new(
+                                "Expected two hex digits after \\x in string literal.".to_string(),
+                                self.span_from(start),
+                            ))
+                        }
+                    }
+                }
+                self.code_point(&digits, start)?
+            }
+            Some('u') => {
+                // One to six hex digits in braces, e.g. \u{1F600}.
+                if self.char_stream.advance() != Some('{') {
+                    return Err(TokenError::new(
+                        "Expected '{' after \\u in string literal.".to_string(),
+                        self.span_from(start),
+                    ));
+                }
+                let digits = self.consume_while(|c| c != '}' && c != '"')?;
+                if self.char_stream.advance() != Some('}') {
+                    return Err(TokenError::new(
+                        "Expected '}' to close \\u{...} escape in string literal.".to_string(),
+                        self.span_from(start),
+                    ));
+                }
+                if digits.len() > 6 {
+                    return Err(TokenError::new(
+                        format!("Too many digits in \\u{{{}}} escape.", digits),
+                        self.span_from(start),
+                    ));
+                }
+                self.code_point(&digits, start)?
+            }
+            Some(c) => {
+                return Err(TokenError::new(
+                    format!("Unknown escape sequence '\\{}' in string literal.", c),
+                    self.span_from(start),
+                ))
+            }
+            None => {
+                return Err(TokenError::end_of_input(
+                    "Reached end of input inside an escape sequence.".to_string(),
+                    self.span_from(start),
+                ))
+            }
+        };
+        Ok(escaped)
     }
 
-    fn consume_identifier(&mut self) -> Result<Token, TokenError> {
-        let identifier = self.consume_while(is_identifier_char)?;
-        Ok(Token::Id(identifier))
+    // Converts the hex digits of an escape sequence into the character they
+    // name.
+    fn code_point(&self, digits: &str, start: Position) -> Result<char, TokenError> {
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .filter(|_| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                TokenError::new(
+                    format!("Invalid character code '{}' in escape sequence.", digits),
+                    self.span_from(start),
+                )
+            })
     }
 
-    fn consume_integer(&mut self) -> Result<Token, TokenError> {
-        let literal = self.consume_while(|c| c.is_ascii_digit())?.parse::<i64>()?;
-        Ok(Token::IntLiteral(literal))
+    // Consumes a raw string such as #"C:\path"# or ##"a "#quoted"# string"##.
+    // Nothing inside is escaped, and the string only ends at a '"' followed
+    // by as many '#'s as opened it. A newline directly after the opening
+    // quote is dropped so that large blocks of text can start on their own
+    // line.
+    fn consume_raw_string(&mut self, start: Position) -> Result<Token, TokenError> {
+        // The first '#' has already been consumed.
+        let hashes = 1 + self.consume_while(|c| c == '#')?.len();
+        if self.char_stream.advance() != Some('"') {
+            return Err(TokenError::new(
+                "Expected '\"' to start raw string literal.".to_string(),
+                self.span_from(start),
+            ));
+        }
+        if self.char_stream.peek() == Some('\n') {
+            self.char_stream.advance();
+        }
+        let mut literal = String::new();
+        loop {
+            match self.char_stream.advance() {
+                Some('"') => {
+                    let mut closing = 0;
+                    while closing < hashes && self.char_stream.peek() == Some('#') {
+                        self.char_stream.advance();
+                        closing += 1;
+                    }
+                    if closing == hashes {
+                        return Ok(Token::StringLiteral(literal));
+                    }
+                    literal.push('"');
+                    literal.extend(std::iter::repeat_n('#', closing));
+                }
+                Some(c) => literal.push(c),
+                None => {
+                    return Err(TokenError::end_of_input(
+                        "Reached end of input inside a raw string literal.".to_string(),
+                        self.span_from(start),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Consumes an identifier or a decimal number.
+    fn consume_atom(&mut self, start: Position) -> Result<Token, TokenError> {
+        let text = self.consume_while(is_identifier_char)?;
+        if looks_numeric(&text) {
+            return parse_decimal(&text).map_err(|e| TokenError::new(e, self.span_from(start)));
+        }
+        match text.as_str() {
+            "true" => Ok(Token::BoolLiteral(true)),
+            "false" => Ok(Token::BoolLiteral(false)),
+            "nil" => Ok(Token::NilLiteral),
+            _ => Ok(Token::Id(text)),
+        }
     }
 
     // Consumes characters, c, from the input until F(c) evaluates to false.
@@ -95,7 +531,7 @@ impl LazyTokenStream {
         F: Fn(char) -> bool,
     {
         let mut chars = Vec::new();
-        while self.char_stream.peek().map_or(false, &func) {
+        while self.char_stream.peek().is_some_and(&func) {
             chars.push(self.char_stream.advance().unwrap());
         }
         Ok(chars.iter().collect::<String>())
@@ -1,53 +1,120 @@
 use crate::error::InterpreterError;
+use crate::span::{Position, Span};
 
 mod lazy_token_stream;
 use lazy_token_stream::LazyTokenStream;
 
 mod charstream;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     OpenParen,
     CloseParen,
     IntLiteral(i64),
+    // An integer written with a radix prefix like #xFF or #b1010. The radix
+    // is kept so tools can print the literal back the way it was written.
+    RadixIntLiteral { value: i64, radix: u32 },
+    FloatLiteral(f64),
+    // #t, #f, #true, #false, true or false.
+    BoolLiteral(bool),
+    // nil, which is the empty list.
+    NilLiteral,
+    // #\a, #\newline, #\x41 and so on.
+    CharLiteral(char),
     StringLiteral(String),
     Id(String),
+    // Reader shorthands: 'x, `x, ,x and ,@x.
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    // '#;' which comments out the expression that follows it. The lexer
+    // can't tell where that expression ends, so the parser skips it.
+    DatumComment,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TriviaKind {
+    Whitespace,
+    // '; ...' up to the end of the line.
+    LineComment,
+    // '#| ... |#', which may nest.
+    BlockComment,
+    // '#;' along with the expression it comments out. These are found by
+    // the parser rather than the lexer.
+    DatumComment,
+}
+
+// Source text that carries no meaning for evaluation but which tools like a
+// formatter need to preserve.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+// A token along with the region of source text it was read from, exactly as
+// it was written, and the trivia that preceded it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+    pub text: String,
+    pub leading_trivia: Vec<Trivia>,
 }
 
 pub trait TokenStream {
-    fn advance(&mut self) -> Result<Option<Token>, TokenError>;
-    fn peek(&mut self) -> Result<Option<Token>, TokenError>;
+    fn advance(&mut self) -> Result<Option<SpannedToken>, TokenError>;
+    fn peek(&mut self) -> Result<Option<SpannedToken>, TokenError>;
+
+    // The position just past the last character read from the input. Once
+    // the stream is exhausted this is the end of input.
+    fn position(&self) -> Position;
+
+    // Trivia following the last token. Only populated once the stream has
+    // been exhausted.
+    fn trailing_trivia(&self) -> &[Trivia];
 }
 
 pub fn lex(s: &str) -> Box<dyn TokenStream> {
     Box::new(LazyTokenStream::new_from_string(s))
 }
 
+// Lexes input as it is read from reader rather than all up front.
+pub fn lex_reader<R: std::io::BufRead + 'static>(reader: R) -> Box<dyn TokenStream> {
+    Box::new(LazyTokenStream::new_from_reader(reader))
+}
+
 #[derive(Debug)]
 pub struct TokenError {
     pub message: String,
+    pub span: Span,
+    // Whether the error is only because the input ended early, e.g. inside a
+    // string. More input could make it go away.
+    pub incomplete: bool,
 }
 
 impl From<TokenError> for InterpreterError {
     fn from(token_error: TokenError) -> InterpreterError {
-        InterpreterError::new("Tokenization Error", &token_error.message)
+        InterpreterError::new("Tokenization Error", &token_error.message).at(token_error.span)
     }
 }
 
-impl From<std::num::ParseIntError> for TokenError {
-    fn from(parse_error: std::num::ParseIntError) -> TokenError {
-        TokenError::new(format!("{}", parse_error))
-    }
-}
-
-impl From<std::str::Utf8Error> for TokenError {
-    fn from(utf8_error: std::str::Utf8Error) -> TokenError {
-        TokenError::new(format!("{}", utf8_error))
+impl TokenError {
+    fn new(message: String, span: Span) -> TokenError {
+        TokenError {
+            message,
+            span,
+            incomplete: false,
+        }
     }
-}
 
-impl TokenError {
-    fn new(message: String) -> TokenError {
-        TokenError { message }
+    fn end_of_input(message: String, span: Span) -> TokenError {
+        TokenError {
+            message,
+            span,
+            incomplete: true,
+        }
     }
 }
@@ -1,16 +1,138 @@
-use crate::rusp::RuspInterpreter;
+use crate::rusp::{Engine, RuspInterpreter};
 use std::env;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::Path;
 
+mod compile;
+mod cst;
 mod error;
 mod eval;
 mod lexer;
 mod parser;
+mod repl;
 mod rusp;
+mod span;
+
+// What to do with the program.
+#[derive(PartialEq)]
+enum Mode {
+    Run,
+    // Only parse the program and report all syntax errors in it.
+    Check,
+    // Parse the program into a concrete syntax tree and print it back out.
+    // The output should be identical to the input.
+    Cst,
+    // Run an interactive session on stdin. This is the default when stdin
+    // is a terminal and no file is given.
+    Repl,
+}
+
+// Command line options. Usage:
+//   lisp-interp [--check | --cst | --repl] [--vm] [--cache] [--optimize] [--max-depth N] [FILE]
+struct Options {
+    // With no file, or a file of '-', the program is read from stdin.
+    path: String,
+    mode: Mode,
+    // --vm runs programs on the bytecode VM rather than the tree walker.
+    engine: Engine,
+    // --cache runs FILE on the VM, saving the compiled program next to it so
+    // later runs can skip compiling. Ignored when reading from stdin.
+    cache: bool,
+    // --optimize rewrites the program into a cheaper equivalent before
+    // running it. The whole program is read first, as with --cache.
+    optimize: bool,
+    // Overrides how deeply evaluation may nest before failing.
+    max_depth: Option<usize>,
+}
+
+fn parse_args() -> Result<Options, error::InterpreterError> {
+    let mut options = Options {
+        path: String::from("-"),
+        mode: Mode::Run,
+        engine: Engine::TreeWalker,
+        cache: false,
+        optimize: false,
+        max_depth: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => options.mode = Mode::Check,
+            "--cst" => options.mode = Mode::Cst,
+            "--repl" => options.mode = Mode::Repl,
+            "--vm" => options.engine = Engine::Vm,
+            "--cache" => {
+                options.engine = Engine::Vm;
+                options.cache = true;
+            }
+            "--optimize" => options.optimize = true,
+            "--max-depth" => {
+                let depth = args.next().and_then(|depth| depth.parse().ok());
+                if depth.is_none() {
+                    return Err(error::InterpreterError::new(
+                        "UsageError",
+                        "Expected a number after --max-depth.",
+                    ));
+                }
+                options.max_depth = depth;
+            }
+            _ => options.path = arg,
+        }
+    }
+    Ok(options)
+}
+
+fn print_cst<R: BufRead + 'static>(reader: R) -> Result<(), error::InterpreterError> {
+    let cst = parser::parse_cst(&mut *lexer::lex_reader(reader))?;
+    print!("{}", cst.to_source());
+    Ok(())
+}
 
 fn main() -> Result<(), error::InterpreterError> {
-    let contents = fs::read_to_string(env::args().nth(1).unwrap())?;
+    let options = parse_args()?;
+    let mut interpreter = RuspInterpreter::new();
+    interpreter.set_engine(options.engine);
+    interpreter.set_optimize(options.optimize);
+    if let Some(max_depth) = options.max_depth {
+        interpreter.set_max_depth(max_depth);
+    }
+    let interactive = options.path == "-" && io::stdin().is_terminal();
+    if options.mode == Mode::Repl || (options.mode == Mode::Run && interactive) {
+        return repl::run_repl(interpreter);
+    }
+    if options.mode == Mode::Run && options.cache && options.path != "-" {
+        let path = Path::new(&options.path);
+        let ret = fs::read_to_string(path)
+            .map_err(error::InterpreterError::from)
+            .and_then(|input| interpreter.run_cached(&input, &path.with_extension("rbc")));
+        return ret.map(|_| ()).map_err(|e| e.in_file(&options.path));
+    }
+    if options.mode == Mode::Run && options.optimize {
+        let (name, input) = if options.path == "-" {
+            ("<stdin>", io::read_to_string(io::stdin()))
+        } else {
+            (options.path.as_str(), fs::read_to_string(&options.path))
+        };
+        let ret = input
+            .map_err(error::InterpreterError::from)
+            .and_then(|input| interpreter.run(&input));
+        return ret.map(|_| ()).map_err(|e| e.in_file(name));
+    }
+    let (name, reader): (&str, io::Result<Box<dyn BufRead>>) = if options.path == "-" {
+        ("<stdin>", Ok(Box::new(io::stdin().lock())))
+    } else {
+        let reader = File::open(&options.path).map(|file| Box::new(BufReader::new(file)) as _);
+        (options.path.as_str(), reader)
+    };
 
     // Run interpreter and throw away return value.
-    RuspInterpreter::new().run(&contents).map(|_| ())
+    let ret = reader
+        .map_err(error::InterpreterError::from)
+        .and_then(|reader| match options.mode {
+            Mode::Check => RuspInterpreter::check_reader(reader),
+            Mode::Cst => print_cst(reader),
+            _ => interpreter.run_reader(reader).map(|_| ()),
+        });
+    ret.map_err(|e| e.in_file(name))
 }
@@ -1,86 +1,420 @@
+use crate::cst::{reader_macro_name, Cst, CstNode};
 use crate::error::InterpreterError;
+use crate::lexer::SpannedToken;
 use crate::lexer::Token;
 use crate::lexer::TokenError;
 use crate::lexer::TokenStream;
+use crate::lexer::{Trivia, TriviaKind};
+use crate::span::Span;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
-    Terminal { token: Token },
-    SExpr { children: Vec<ASTNode> },
-    Identifier { name: String },
-    Program { statements: Vec<ASTNode> },
+    Terminal {
+        token: Token,
+        span: Span,
+    },
+    SExpr {
+        // Shared so that evaluation can hold on to parts of the tree cheaply.
+        children: Rc<[ASTNode]>,
+        span: Span,
+    },
+    Identifier {
+        name: String,
+        span: Span,
+    },
+    // An identifier which the resolver found bound by an enclosing let or
+    // function. Its value is at index in the scope depth scopes out from the
+    // innermost one.
+    LocalRef {
+        name: Rc<str>,
+        depth: usize,
+        index: usize,
+        span: Span,
+    },
+    // An identifier which the resolver found isn't bound locally, so must be
+    // a global.
+    GlobalRef {
+        name: Rc<str>,
+        span: Span,
+    },
+    Program {
+        statements: Vec<ASTNode>,
+        span: Span,
+    },
+}
+
+impl ASTNode {
+    pub fn span(&self) -> Span {
+        match self {
+            ASTNode::Terminal { span, .. }
+            | ASTNode::SExpr { span, .. }
+            | ASTNode::Identifier { span, .. }
+            | ASTNode::LocalRef { span, .. }
+            | ASTNode::GlobalRef { span, .. }
+            | ASTNode::Program { span, .. } => *span,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ParseError {
-    message: String,
+    pub message: String,
+    pub span: Span,
+    // Whether the error is only because the input ended early. See
+    // TokenError::incomplete.
+    pub incomplete: bool,
 }
 
 impl From<TokenError> for ParseError {
     fn from(token_error: TokenError) -> ParseError {
         ParseError {
             message: token_error.message,
+            span: token_error.span,
+            incomplete: token_error.incomplete,
         }
     }
 }
 
 impl From<ParseError> for InterpreterError {
     fn from(parse_error: ParseError) -> InterpreterError {
-        InterpreterError::new("ParseError", &parse_error.message)
+        InterpreterError::new("ParseError", &parse_error.message).at(parse_error.span)
+    }
+}
+
+impl From<Vec<ParseError>> for InterpreterError {
+    fn from(parse_errors: Vec<ParseError>) -> InterpreterError {
+        let mut errors = parse_errors.into_iter().map(InterpreterError::from);
+        let first = errors
+            .next()
+            .unwrap_or_else(|| InterpreterError::new("ParseError", "Unknown parse error."));
+        errors.fold(first, InterpreterError::and)
     }
 }
 
 impl ParseError {
-    fn new(message: &str) -> ParseError {
+    fn new(message: &str, span: Span) -> ParseError {
         ParseError {
             message: String::from(message),
+            span,
+            incomplete: false,
         }
     }
-}
 
-pub fn parse(tokens: &mut dyn TokenStream) -> Result<ASTNode, ParseError> {
-    let mut statements = Vec::new();
-    while tokens.peek()?.is_some() {
-        let expr = parse_expr(tokens)?;
-        statements.push(expr);
+    fn end_of_input(message: &str, span: Span) -> ParseError {
+        ParseError {
+            message: String::from(message),
+            span,
+            incomplete: true,
+        }
     }
-    Ok(ASTNode::Program { statements })
 }
 
-fn read_token_or_fail(tokens: &mut dyn TokenStream) -> Result<Token, ParseError> {
-    tokens.advance()?.ok_or_else(|| {
-        ParseError::new("Attempted to read the next token, but there are none left.")
+// Parses all of the input. Rather than stopping at the first problem, the
+// parser skips to the end of the top level form containing it and carries on,
+// so every error in the input is returned.
+pub fn parse(tokens: &mut dyn TokenStream) -> Result<ASTNode, Vec<ParseError>> {
+    parse_cst(tokens).map(|cst| cst.to_ast())
+}
+
+// Like parse, but returns the lossless concrete syntax tree.
+pub fn parse_cst(tokens: &mut dyn TokenStream) -> Result<Cst, Vec<ParseError>> {
+    let mut parser = Parser::new(tokens);
+    let mut forms = Vec::new();
+    let mut errors = Vec::new();
+    loop {
+        match parser.next_form() {
+            Ok(Some(form)) => forms.push(form),
+            Ok(None) => break,
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut trailing_trivia = std::mem::take(&mut parser.pending_trivia);
+    trailing_trivia.extend_from_slice(parser.tokens.trailing_trivia());
+    Ok(Cst {
+        forms,
+        trailing_trivia,
     })
 }
 
-fn consume_close_paren(tokens: &mut dyn TokenStream) -> Result<(), ParseError> {
-    let tok = read_token_or_fail(tokens)?;
-    match tok {
-        Token::CloseParen => Ok(()),
-        _ => Err(ParseError::new(
-            format!("Expected ')', but found {:?}.", tok).as_str(),
-        )),
+// How much of a program some input contains. Used by interactive front ends
+// to decide whether to evaluate what has been typed so far or wait for more.
+#[derive(Debug)]
+pub enum InputStatus {
+    // Every form in the input is finished.
+    Complete,
+    // The input is fine so far but stops part way through a form, e.g.
+    // '(defun foo (x)'. depth is the number of parens left open.
+    Incomplete { depth: usize },
+    // The input has errors which no amount of further input would fix.
+    Invalid(Vec<ParseError>),
+}
+
+// Classifies tokens as a complete, incomplete or invalid program.
+pub fn classify(tokens: &mut dyn TokenStream) -> InputStatus {
+    let mut parser = Parser::new(tokens);
+    let mut errors = Vec::new();
+    let mut open_depth = None;
+    loop {
+        match parser.try_next_form() {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(e) => {
+                if e.incomplete {
+                    open_depth = Some(parser.depth);
+                } else {
+                    errors.push(e);
+                }
+                parser.recover();
+            }
+        }
+    }
+    match open_depth {
+        _ if !errors.is_empty() => InputStatus::Invalid(errors),
+        Some(depth) => InputStatus::Incomplete { depth },
+        None => InputStatus::Complete,
     }
 }
 
-fn next_is_close_paren(tokens: &mut dyn TokenStream) -> Result<bool, ParseError> {
-    let next_tok = tokens.peek()?;
-    Ok(matches!(next_tok, Some(Token::CloseParen)))
+// Parses the next top level form, or returns None at the end of input. Only
+// the tokens making up that form are read, so this can be used to evaluate
+// input one form at a time as it arrives. After an error the input is left at
+// the start of the following form.
+pub fn parse_next(tokens: &mut dyn TokenStream) -> Result<Option<ASTNode>, ParseError> {
+    let form = Parser::new(tokens).next_form()?;
+    Ok(form.map(|form| form.to_ast()))
 }
 
-pub fn parse_expr(tokens: &mut dyn TokenStream) -> Result<ASTNode, ParseError> {
-    let next_tok = read_token_or_fail(tokens).unwrap();
+// How deeply expressions may nest, counting each '(' and reader shorthand
+// like 'x. The parser and later passes recurse once per level, so without a
+// limit deeply nested input would overflow the stack.
+const MAX_NESTING: usize = 1000;
+
+struct Parser<'a> {
+    tokens: &'a mut dyn TokenStream,
+    // The number of '('s read which haven't been closed yet.
+    depth: usize,
+    // The number of expressions being parsed which enclose the current one.
+    nesting: usize,
+    // Datum comments, and the trivia around them, which have been skipped
+    // over but not yet attached to a token. They become leading trivia of
+    // the next token read.
+    pending_trivia: Vec<Trivia>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a mut dyn TokenStream) -> Parser<'a> {
+        Parser {
+            tokens,
+            depth: 0,
+            nesting: 0,
+            pending_trivia: Vec::new(),
+        }
+    }
+
+    fn next_form(&mut self) -> Result<Option<CstNode>, ParseError> {
+        let form = self.try_next_form();
+        if form.is_err() {
+            self.recover();
+        }
+        form
+    }
+
+    // Like next_form, but leaves the input where it is after an error.
+    fn try_next_form(&mut self) -> Result<Option<CstNode>, ParseError> {
+        self.skip_datum_comments()?;
+        if self.tokens.peek()?.is_some() {
+            self.parse_expr().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Skips the rest of the top level form an error was found in by reading
+    // until every open paren has been closed. Any further errors found along
+    // the way are dropped since they're likely caused by the first one.
+    fn recover(&mut self) {
+        while self.depth > 0 {
+            match self.tokens.advance() {
+                Ok(Some(SpannedToken {
+                    token: Token::OpenParen,
+                    ..
+                })) => self.depth += 1,
+                Ok(Some(SpannedToken {
+                    token: Token::CloseParen,
+                    ..
+                })) => self.depth -= 1,
+                Ok(Some(_)) | Err(_) => {}
+                Ok(None) => break,
+            }
+        }
+        self.depth = 0;
+        self.pending_trivia.clear();
+    }
+
+    // Reads the next token, attaching any pending trivia to it.
+    fn advance(&mut self) -> Result<Option<SpannedToken>, ParseError> {
+        let mut token = self.tokens.advance()?;
+        if let Some(token) = &mut token {
+            if !self.pending_trivia.is_empty() {
+                let mut trivia = std::mem::take(&mut self.pending_trivia);
+                trivia.append(&mut token.leading_trivia);
+                token.leading_trivia = trivia;
+            }
+        }
+        Ok(token)
+    }
+
+    fn end_of_input(&self) -> Span {
+        Span::point(self.tokens.position())
+    }
+
+    // Skips any '#;' datum comments, along with the expressions they comment
+    // out, at the front of the input. They are kept as pending trivia so the
+    // concrete syntax tree still has them.
+    fn skip_datum_comments(&mut self) -> Result<(), ParseError> {
+        while let Some(SpannedToken {
+            token: Token::DatumComment,
+            ..
+        }) = self.tokens.peek()?
+        {
+            let mut marker = match self.advance()? {
+                Some(marker) => marker,
+                None => break,
+            };
+            let mut trivia = std::mem::take(&mut marker.leading_trivia);
+            let message = "Expected an expression to follow this '#;'.";
+            if self.tokens.peek()?.is_none() {
+                return Err(ParseError::end_of_input(message, marker.span));
+            }
+            if self.next_is_close_paren()? {
+                return Err(ParseError::new(message, marker.span));
+            }
+            // Parsing the datum handles any datum comments nested before it.
+            let datum = self.parse_expr()?;
+            let mut text = marker.text;
+            datum.write_source(&mut text);
+            trivia.push(Trivia {
+                kind: TriviaKind::DatumComment,
+                text,
+                span: marker.span.to(datum.span()),
+            });
+            self.pending_trivia = trivia;
+        }
+        Ok(())
+    }
+
+    fn next_is_close_paren(&mut self) -> Result<bool, ParseError> {
+        let next_tok = self.tokens.peek()?;
+        Ok(matches!(
+            next_tok,
+            Some(SpannedToken {
+                token: Token::CloseParen,
+                ..
+            })
+        ))
+    }
+
+    fn parse_expr(&mut self) -> Result<CstNode, ParseError> {
+        if self.nesting == MAX_NESTING {
+            let span = match self.tokens.peek()? {
+                Some(tok) => tok.span,
+                None => self.end_of_input(),
+            };
+            return Err(ParseError::new(
+                &format!(
+                    "Expressions are nested too deeply. At most {} levels are allowed.",
+                    MAX_NESTING
+                ),
+                span,
+            ));
+        }
+        self.nesting += 1;
+        let expr = self.parse_nested_expr();
+        self.nesting -= 1;
+        expr
+    }
+
+    // Parses an expression once parse_expr has checked it isn't nested too
+    // deeply.
+    fn parse_nested_expr(&mut self) -> Result<CstNode, ParseError> {
+        self.skip_datum_comments()?;
+        let token = match self.advance()? {
+            Some(token) => token,
+            None => {
+                return Err(ParseError::end_of_input(
+                    "Expected an expression, but reached the end of input.",
+                    self.end_of_input(),
+                ))
+            }
+        };
 
-    if let Token::OpenParen = next_tok {
-        let mut nodes = Vec::new();
-        while !next_is_close_paren(tokens)? {
-            nodes.push(parse_expr(tokens)?);
+        match token.token {
+            Token::OpenParen => self.parse_sexpr(token),
+            Token::CloseParen => Err(ParseError::new(
+                "Found ')' without a matching '('.",
+                token.span,
+            )),
+            Token::DatumComment => Err(ParseError::new("Unexpected '#;'.", token.span)),
+            _ if reader_macro_name(&token.token).is_some() => self.parse_reader_macro(token),
+            _ => Ok(CstNode::Atom(token)),
+        }
+    }
+
+    // Parses the rest of an s-expression opened by open.
+    fn parse_sexpr(&mut self, open: SpannedToken) -> Result<CstNode, ParseError> {
+        self.depth += 1;
+        let mut children = Vec::new();
+        loop {
+            self.skip_datum_comments()?;
+            if self.next_is_close_paren()? {
+                break;
+            }
+            if self.tokens.peek()?.is_none() {
+                return Err(ParseError::end_of_input(
+                    "Reached the end of input before this '(' was closed.",
+                    open.span,
+                ));
+            }
+            children.push(self.parse_expr()?);
+        }
+        let close = match self.advance()? {
+            Some(close) => close,
+            None => {
+                return Err(ParseError::end_of_input(
+                    "Reached the end of input before this '(' was closed.",
+                    open.span,
+                ))
+            }
+        };
+        self.depth -= 1;
+        Ok(CstNode::List {
+            open,
+            children,
+            close,
+        })
+    }
+
+    // Parses the expression following a reader shorthand like '.
+    fn parse_reader_macro(&mut self, prefix: SpannedToken) -> Result<CstNode, ParseError> {
+        let message = format!(
+            "Expected an expression to follow this {}.",
+            reader_macro_name(&prefix.token).unwrap_or_default()
+        );
+        self.skip_datum_comments()?;
+        if self.tokens.peek()?.is_none() {
+            return Err(ParseError::end_of_input(&message, prefix.span));
+        }
+        if self.next_is_close_paren()? {
+            return Err(ParseError::new(&message, prefix.span));
         }
-        consume_close_paren(tokens)?;
-        Ok(ASTNode::SExpr { children: nodes })
-    } else if let Token::Id(identifier) = next_tok {
-        Ok(ASTNode::Identifier { name: identifier })
-    } else {
-        Ok(ASTNode::Terminal { token: next_tok })
+        let expr = self.parse_expr()?;
+        Ok(CstNode::Prefixed {
+            prefix,
+            expr: Box::new(expr),
+        })
     }
 }
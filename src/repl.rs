@@ -0,0 +1,54 @@
+use crate::error::InterpreterError;
+use crate::lexer::lex;
+use crate::parser::{classify, InputStatus};
+use crate::rusp::RuspInterpreter;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "rusp> ";
+const CONTINUATION_PROMPT: &str = "...   ";
+
+// Reads lines from stdin until they make up complete forms, then evaluates
+// them and prints the result. Errors are reported without ending the session.
+pub fn run_repl(mut interpreter: RuspInterpreter) -> Result<(), InterpreterError> {
+    let mut stdin = io::stdin().lock();
+    let mut buffer = String::new();
+    let mut prompt = String::from(PROMPT);
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        let at_end_of_input = stdin.read_line(&mut line)? == 0;
+        buffer.push_str(&line);
+
+        let status = classify(&mut *lex(&buffer));
+        if let InputStatus::Incomplete { depth } = status {
+            if !at_end_of_input {
+                // Indent continuation lines to match the open parens.
+                prompt = format!("{}{}", CONTINUATION_PROMPT, "  ".repeat(depth));
+                continue;
+            }
+        }
+        if let InputStatus::Invalid(errors) = status {
+            eprintln!("{}", InterpreterError::from(errors).in_file("<repl>"));
+        } else if !buffer.trim().is_empty() {
+            // Incomplete input at the end of input is run anyway so the
+            // parse error gets reported. Forms are run one at a time, like a
+            // file, so functions may refer to ones entered later.
+            match interpreter.run_reader(io::Cursor::new(buffer.clone())) {
+                Ok(value) => {
+                    if let Ok(s) = value.runtime_to_str() {
+                        println!("{}", s);
+                    }
+                }
+                Err(e) => eprintln!("{}", e.in_file("<repl>")),
+            }
+        }
+
+        if at_end_of_input {
+            return Ok(());
+        }
+        buffer.clear();
+        prompt = String::from(PROMPT);
+    }
+}
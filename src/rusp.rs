@@ -1,38 +1,180 @@
+use crate::compile::{cache, compile_program, vm};
 use crate::error::InterpreterError;
 use crate::eval::default_env;
-use crate::eval::environment::Context;
+use crate::eval::environment::{Context, Environment};
+use crate::eval::error::RuntimeError;
 use crate::eval::eval_program;
+use crate::eval::expander::expand;
 use crate::eval::io::IOStream;
+use crate::eval::optimizer::optimize;
+use crate::eval::resolver::{resolve, resolve_forms};
 use crate::eval::value::Value;
-use crate::lexer::lex;
-use crate::parser::parse;
+use crate::lexer::{lex, lex_reader};
+use crate::parser::{parse, parse_next, ASTNode};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
 
-pub struct RuspInterpreter {}
+// How programs are run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // Walk the syntax tree with eval.
+    TreeWalker,
+    // Compile to bytecode and run it on the virtual machine in compile::vm.
+    Vm,
+}
+
+// Definitions made by one call to run or run_reader remain visible to later
+// calls on the same interpreter.
+pub struct RuspInterpreter {
+    env: Environment,
+    context: Context,
+    engine: Engine,
+    // Whether whole programs are optimized before running. See optimize.
+    optimize: bool,
+}
 
 impl RuspInterpreter {
     pub fn new() -> Self {
-        RuspInterpreter {}
+        RuspInterpreter {
+            env: default_env(),
+            context: Context::new(IOStream::new_stdout()),
+            engine: Engine::TreeWalker,
+            optimize: false,
+        }
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // Limits how deeply evaluation may nest. See Context::max_depth.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.context.max_depth = max_depth;
+    }
+
+    // Prepares all of input before evaluating any of it, so the whole
+    // program can be optimized. Returns the value of the last form. Otherwise
+    // this behaves just like run_reader: input which can't be prepared as a
+    // whole, e.g. because a later form has a syntax error, is run by
+    // run_reader instead so the forms before the error still run.
+    pub fn run(&mut self, input: &str) -> Result<Value, InterpreterError> {
+        let ast = match self.prepare(input) {
+            Ok(ast) => ast,
+            Err(_) => return self.run_reader(io::Cursor::new(input.to_owned())),
+        };
+
+        let ret = self.evaluate(&ast);
+        self.flushed(ret).map_err(InterpreterError::from)
+    }
+
+    // Runs input on the VM like run, using the program saved in cache_path if
+    // it was compiled from the same input. Otherwise input is compiled and
+    // saved there for next time. Compiled code depends on what's defined when
+    // it's compiled, so this should only be used on a new interpreter.
+    // Programs defining macros aren't saved, since a cached program isn't
+    // expanded and so wouldn't define them. Input which can't be prepared as a
+    // whole, e.g. because a later form has a syntax error, is run by
+    // run_reader instead so the forms before the error still run.
+    // Problems reading or writing the cache file are ignored since it can
+    // always be compiled again.
+    pub fn run_cached(
+        &mut self,
+        input: &str,
+        cache_path: &Path,
+    ) -> Result<Value, InterpreterError> {
+        let cached = fs::read(cache_path)
+            .ok()
+            .and_then(|bytes| cache::decode(&bytes, input, self.optimize).ok());
+        let function = match cached {
+            Some(function) => function,
+            None => {
+                let ast = match self.prepare(input) {
+                    Ok(ast) => ast,
+                    Err(_) => return self.run_reader(io::Cursor::new(input.to_owned())),
+                };
+                let function = compile_program(&ast, &self.env);
+                let defines_macros = self
+                    .env
+                    .any_global(|value| matches!(value, Value::Macro(_)));
+                if !defines_macros {
+                    if let Ok(bytes) = cache::encode(&function, input, self.optimize) {
+                        let _ = fs::write(cache_path, bytes);
+                    }
+                }
+                function
+            }
+        };
+
+        let ret = vm::run(&self.env, &mut self.context, function);
+        self.flushed(ret).map_err(InterpreterError::from)
     }
 
-    pub fn run(&self, input: &str) -> Result<Value, InterpreterError> {
+    // Parses, expands and resolves the whole program in input, optimizing it
+    // if asked to. It's resolved by the rules run_reader uses, so the same
+    // programs are accepted. Unbound identifiers are reported as written,
+    // before optimizing removes any of them.
+    fn prepare(&mut self, input: &str) -> Result<ASTNode, InterpreterError> {
         let mut tokens = lex(input);
         let ast = parse(&mut *tokens)?;
+        let ast = expand(&ast, &self.env, &mut self.context)?;
+        let resolved = resolve_forms(&ast, &self.env)?;
+        if !self.optimize {
+            return Ok(resolved);
+        }
+        Ok(resolve_forms(&optimize(&ast, &self.env), &self.env)?)
+    }
 
-        //let mut context = Context::new(IOStream::new_in_memory_buffer());
-        let mut context = Context::new(IOStream::new_stdout());
+    // Parses, expands and resolves everything read from reader without
+    // evaluating any of it, other than the macros it uses. Reports every
+    // syntax error found or, if there are none, every unbound identifier.
+    pub fn check_reader<R: BufRead + 'static>(reader: R) -> Result<(), InterpreterError> {
+        let mut tokens = lex_reader(reader);
+        let env = default_env();
+        // Anything macros write while expanding is thrown away.
+        let mut context = Context::new(IOStream::new_in_memory_buffer());
+        let ast = expand(&parse(&mut *tokens)?, &env, &mut context)?;
+        resolve(&ast, &env)?;
+        Ok(())
+    }
 
-        let ret = eval_program(&mut default_env(), &mut context, &ast)
-            // I'm not sure why this is needed. We have a proper From defined for
-            // this conversion, but something is preventing this file from seeing it.
-            .map_err(|re| InterpreterError::new("RuntimeError", &re.message));
+    // Reads, parses and evaluates one top level form at a time, so each form
+    // runs as soon as it has arrived and the input never needs to be held in
+    // memory all at once. Returns the value of the last form.
+    pub fn run_reader<R: BufRead + 'static>(
+        &mut self,
+        reader: R,
+    ) -> Result<Value, InterpreterError> {
+        let mut tokens = lex_reader(reader);
+        let mut last = Value::Unit;
+        while let Some(form) = parse_next(&mut *tokens)? {
+            let form = expand(&form, &self.env, &mut self.context)?;
+            let form = resolve(&form, &self.env)?;
+            let ret = self.evaluate(&form);
+            last = self.flushed(ret)?;
+        }
+        Ok(last)
+    }
 
-        let captured_output = if let IOStream::InMemoryBuffer(vec) = context.stdout {
-            vec
-        } else {
-            Vec::new()
-        };
-        println!("{}", String::from_utf8(captured_output).unwrap());
+    // Flushes anything written while producing ret. Failing to flush is only
+    // reported if nothing else went wrong.
+    fn flushed(&mut self, ret: Result<Value, RuntimeError>) -> Result<Value, RuntimeError> {
+        let flushed = self.context.stdout.flush();
+        ret.and_then(|value| flushed.map(|_| value))
+    }
 
-        ret
+    // Evaluates a resolved program or top level form with the chosen engine.
+    fn evaluate(&mut self, ast: &ASTNode) -> Result<Value, RuntimeError> {
+        match self.engine {
+            Engine::TreeWalker => eval_program(&self.env, &mut self.context, ast),
+            Engine::Vm => {
+                let function = compile_program(ast, &self.env);
+                vm::run(&self.env, &mut self.context, function)
+            }
+        }
     }
 }
@@ -1,8 +1,859 @@
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process::{Command, Output, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SCRIPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    // Writes source to a fresh script file and returns its path.
+    fn write_script(source: &str) -> PathBuf {
+        let id = SCRIPT_COUNT.fetch_add(1, Ordering::SeqCst);
+        let path =
+            std::env::temp_dir().join(format!("rusp-test-{}-{}.lisp", std::process::id(), id));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        path
+    }
+
+    // Runs the interpreter binary over source.
+    fn run(source: &str) -> (PathBuf, Output) {
+        let path = write_script(source);
+        let output = Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+            .arg(&path)
+            .output()
+            .unwrap();
+        (path, output)
+    }
+
+    // Runs the interpreter with source piped to stdin.
+    fn run_stdin(source: &str) -> Output {
+        run_stdin_with_args(&[], source)
+    }
+
+    fn run_stdin_with_args(args: &[&str], source: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    fn stdout_of(source: &str) -> String {
+        let (_, output) = run(source);
+        assert!(
+            output.status.success(),
+            "script failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn stderr_of(source: &str) -> (PathBuf, String) {
+        let (path, output) = run(source);
+        assert!(!output.status.success(), "script unexpectedly succeeded");
+        (path, String::from_utf8(output.stderr).unwrap())
+    }
 
     #[test]
     fn a_test() {
         assert!(false);
     }
+
+    #[test]
+    fn runs_a_program() {
+        assert!(stdout_of("(write (+ 1 2))").starts_with('3'));
+    }
+
+    #[test]
+    fn runtime_errors_report_file_line_and_column() {
+        let (path, stderr) = stderr_of("(write \"a\")\n  (undefined 1)");
+        assert!(
+            stderr.contains(&format!("{}:2:4", path.display())),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn token_errors_report_file_line_and_column() {
+        let (path, stderr) = stderr_of("\n\n (+ 99999999999999999999 1)");
+        assert!(
+            stderr.contains(&format!("{}:3:5", path.display())),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let source = "; line comment\n\
+                      #| block #| nested |# comment |#\n\
+                      (write (+ 1 #;(ignored) #;#; 5 6 2)) ; trailing\n\
+                      #;(write \"skipped\")";
+        assert!(stdout_of(source).starts_with('3'));
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_an_error() {
+        let (_, stderr) = stderr_of("(write 1)\n#| never closed");
+        assert!(stderr.contains("block comment"), "{}", stderr);
+    }
+
+    #[test]
+    fn string_escapes() {
+        let source = r#"(write "tab\tquote\"slash\\\x41\u{e9}\n")"#;
+        assert!(stdout_of(source).starts_with("tab\tquote\"slash\\A\u{e9}\n"));
+    }
+
+    #[test]
+    fn raw_strings_are_not_escaped() {
+        let source = "(write ##\"\n\\n \"# inside\"##)";
+        assert!(stdout_of(source).starts_with("\\n \"# inside"));
+    }
+
+    #[test]
+    fn unknown_escape_is_an_error() {
+        let (path, stderr) = stderr_of("(write \"ok\\q\")");
+        assert!(
+            stderr.contains(&format!("{}:1:11", path.display())),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("Unknown escape"), "{}", stderr);
+    }
+
+    #[test]
+    fn hex_escape_needs_two_hex_digits() {
+        for source in ["(write \"\\x4\")", "(write \"\\xg1\")"] {
+            let (path, stderr) = stderr_of(source);
+            assert!(
+                stderr.contains(&format!("{}:1:9", path.display())),
+                "{}",
+                stderr
+            );
+            assert!(stderr.contains("two hex digits after \\x"), "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn unterminated_string_is_an_error() {
+        let (_, stderr) = stderr_of("(write \"never closed)");
+        assert!(stderr.contains("inside a string literal"), "{}", stderr);
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let source = "(defun show (x) (write x) (write \" \"))\n\
+                      (show -5) (show 3.25) (show #xFF) (show #b-101) (show 1e3) (show .5)\n\
+                      (let x1 7 (show x1))";
+        assert!(stdout_of(source).starts_with("-5 3.25 255 -5 1000.0 0.5 7 "));
+    }
+
+    #[test]
+    fn integer_overflow_is_a_token_error() {
+        let (_, stderr) = stderr_of("(write 9223372036854775808)");
+        assert!(stderr.contains("does not fit in 64 bits"), "{}", stderr);
+    }
+
+    #[test]
+    fn boolean_nil_and_char_literals() {
+        let source = "(write (list #t #f true false nil () #\\a #\\( #\\x41))\n\
+                      (write (if #f \"yes\" \"no\"))\n\
+                      (write #\\newline)";
+        assert!(stdout_of(source).starts_with("(true false true false () () a ( A)no\n"));
+    }
+
+    #[test]
+    fn space_char_literal_ends_before_a_name() {
+        let source = "(let a 1 (let b 2 (write (list #\\ a #\\(b))))";
+        assert!(stdout_of(source).starts_with("(  1 ( 2)"));
+    }
+
+    #[test]
+    fn unknown_character_name_is_an_error() {
+        let (_, stderr) = stderr_of("(write #\\bogus)");
+        assert!(stderr.contains("Unknown character name"), "{}", stderr);
+    }
+
+    #[test]
+    fn quote_and_quasiquote() {
+        let source = "(write '(a \"b\" 1 #t))\n\
+                      (let b 2 (let c (list 3 4) (write `(a ,b ,@c d))))\n\
+                      (write (eval (list '+ 40 2)))";
+        assert!(stdout_of(source).starts_with("(a b 1 true)(a 2 3 4 d)42"));
+    }
+
+    #[test]
+    fn unquote_outside_quasiquote_is_an_error() {
+        let (_, stderr) = stderr_of("(write ,x)");
+        assert!(stderr.contains("outside of a quasiquote"), "{}", stderr);
+    }
+
+    #[test]
+    fn reads_program_from_stdin() {
+        let output = run_stdin("(defun twice (x) (+ x x))\n(write (twice 21))");
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout).unwrap().starts_with("42"));
+    }
+
+    #[test]
+    fn streamed_forms_run_before_later_errors() {
+        let output = run_stdin("(write \"caf\u{e9}\")\n(write (+ 1 \"x\"))");
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stdout)
+            .unwrap()
+            .starts_with("caf\u{e9}"));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("<stdin>:2:8"), "{}", stderr);
+    }
+
+    #[test]
+    fn each_form_runs_before_the_next_arrives() {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut byte = [0];
+            while let Ok(1) = std::io::Read::read(&mut stdout, &mut byte) {
+                sender.send(byte[0]).unwrap();
+            }
+        });
+        stdin.write_all(b"(write 1)\n").unwrap();
+        stdin.flush().unwrap();
+        let first = receiver.recv_timeout(std::time::Duration::from_secs(10));
+        assert_eq!(first, Ok(b'1'));
+        stdin.write_all(b"(write 2)\n").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), b"2");
+    }
+
+    #[test]
+    fn complete_forms_run_when_later_input_fails_to_lex() {
+        let output = run_stdin("(write \"a\") \"\\q");
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "a");
+        let output = run_stdin("(write \"b\") #\\bogus");
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "b");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Unknown character name"), "{}", stderr);
+    }
+
+    #[test]
+    fn check_reports_every_parse_error() {
+        let output = run_stdin_with_args(
+            &["--check"],
+            "(write \"a\"))\n(write \"bad \\q\")\n(defun f (x) (write x)\n(write \"b\")",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("<stdin>:1:12"), "{}", stderr);
+        assert!(stderr.contains("without a matching '('"), "{}", stderr);
+        assert!(stderr.contains("<stdin>:2:13"), "{}", stderr);
+        assert!(stderr.contains("<stdin>:3:1"), "{}", stderr);
+        assert!(stderr.contains("before this '(' was closed"), "{}", stderr);
+    }
+
+    #[test]
+    fn deeply_nested_input_is_a_parse_error() {
+        let parens = "(".repeat(200_000);
+        let quotes = format!("(write {}a)", "'".repeat(200_000));
+        for args in [&[][..], &["--check"][..]] {
+            for source in [&parens, &quotes] {
+                let output = run_stdin_with_args(args, source);
+                // A stack overflow aborts rather than exiting with a code.
+                assert_eq!(output.status.code(), Some(1));
+                let stderr = String::from_utf8(output.stderr).unwrap();
+                assert!(stderr.contains("nested too deeply"), "{}", stderr);
+            }
+        }
+    }
+
+    #[test]
+    fn unbalanced_input_does_not_panic() {
+        for source in [")", "(", "'", "(a #;", "(write ')"] {
+            let output = run_stdin(source);
+            assert!(!output.status.success());
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.contains("ParseError"), "{}: {}", source, stderr);
+        }
+    }
+
+    #[test]
+    fn repl_waits_for_incomplete_forms() {
+        let output = run_stdin_with_args(
+            &["--repl"],
+            "(defun f (x)\n  (+ x\n     1))\n\"multi\nline\"\n)\n(f 2)\n",
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stdout.contains("multi\nline\n"), "{}", stdout);
+        assert!(stdout.contains("3\n"), "{}", stdout);
+        // Only the stray paren is an error.
+        assert_eq!(stderr.matches("ParseError").count(), 1, "{}", stderr);
+        assert!(stderr.contains("without a matching '('"), "{}", stderr);
+    }
+
+    #[test]
+    fn repl_reports_errors_in_later_forms_on_a_line() {
+        let output = run_stdin_with_args(
+            &["--repl"],
+            "(write 1) (a #;)\n(write 2) #\\bogus\n(write 3)\n",
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        // Neither line waits for more input.
+        assert_eq!(stdout, "rusp> rusp> rusp> 3rusp> ", "{}", stderr);
+        assert!(stderr.contains("<repl>:1:14"), "{}", stderr);
+        assert!(stderr.contains("follow this '#;'"), "{}", stderr);
+        assert!(stderr.contains("<repl>:1:11"), "{}", stderr);
+        assert!(stderr.contains("Unknown character name"), "{}", stderr);
+    }
+
+    #[test]
+    fn input_status_reflects_the_first_unfinished_form() {
+        // Only the string is left open, so the continuation isn't indented.
+        let output = run_stdin_with_args(&["--repl"], "(write 1) \"abc\ndef\"\n");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("rusp> ...   1abc\ndef\n"), "{}", stdout);
+
+        let output = run_stdin("(a #;)");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("follow this '#;'"), "{}", stderr);
+        assert!(!stderr.contains("without a matching"), "{}", stderr);
+    }
+
+    #[test]
+    fn cst_prints_source_back_byte_for_byte() {
+        let source = "; header\n#| block #| nested |# |#\n\
+                      (defun   f (x)  ; why\n  #;(old code) #; #; a b\n\
+                      \t'( 1 #x1F  \"s\\n\" #\\a ,@x 2.50 ) #;c)\n  #;(trailing)  \n";
+        let output = run_stdin_with_args(&["--cst"], source);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), source);
+    }
+
+    #[test]
+    fn closures_capture_their_defining_environment() {
+        let source = "(defun make-adder (n) (lambda (x) (+ x n)))\n\
+                      (write (let add5 (make-adder 5) (add5 10)))\n\
+                      (write (let n 1 (let f (lambda (x) (+ x n)) (let n 100 (f 1)))))\n\
+                      (defun even (n) (if (< n 1) true (odd (- n 1))))\n\
+                      (defun odd (n) (if (< n 1) false (even (- n 1))))\n\
+                      (write (even 10))\n";
+        assert_eq!(stdout_of(source), "152true");
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_the_stack() {
+        let source = "(defun countdown (x) (if (< x 1) \"done\" (countdown (- x 1))))\n\
+                      (write (countdown 1000000))\n\
+                      (defun even (n) (if (< n 1) true (odd (- n 1))))\n\
+                      (defun odd (n) (if (< n 1) false (let m (- n 1) (even m))))\n\
+                      (write (even 100001))\n";
+        assert_eq!(stdout_of(source), "donefalse");
+    }
+
+    #[test]
+    fn deep_recursion_does_not_overflow_the_stack() {
+        let source = "(defun count (n) (if (< n 1) 0 (+ 1 (count (- n 1)))))\n\
+                      (write (count 100000))\n";
+        assert_eq!(stdout_of(source), "100000");
+
+        let output = run_stdin_with_args(&["--max-depth", "1000"], source);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Stack depth exceeded"), "{}", stderr);
+    }
+
+    #[test]
+    fn deeply_nested_lists_do_not_overflow_the_stack() {
+        let build = "(defun build (n) (if (< n 1) nil (list n (build (- n 1)))))\n";
+        let source = format!("{}(let xs (build 100000) (write \"built\"))\n", build);
+        assert_eq!(stdout_of(&source), "built");
+
+        let (path, output) = run(&format!("{}(let xs (build 100000) (write xs))\n", build));
+        let expected: String = (1..=100000).rev().map(|n| format!("({} ", n)).collect();
+        let expected = format!("{}(){}", expected, ")".repeat(100000));
+        assert!(String::from_utf8(output.stdout).unwrap() == expected);
+        assert_engines_agree(&path);
+    }
+
+    #[test]
+    fn unbound_identifiers_are_reported_before_running() {
+        let output = run_stdin_with_args(
+            &["--check"],
+            "(defun f (x) (+ x (g y)))\n(defun g (z) (undefined z))\n(write f)\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("<stdin>:1:22"), "{}", stderr);
+        assert!(stderr.contains("<stdin>:2:15"), "{}", stderr);
+        assert_eq!(stderr.matches("ResolveError").count(), 2, "{}", stderr);
+
+        // Nothing runs when the form itself refers to something unbound.
+        let output = run_stdin("(write (let x 1 (+ x y)))");
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("unbound identifier \"y\""), "{}", stderr);
+    }
+
+    #[test]
+    fn locals_are_visible_to_eval_and_quasiquote() {
+        let source = "(write (let x 5 (eval '(+ x 1))))\n\
+                      (write (let x 1 `(a ,x ,@(list x x))))\n";
+        assert_eq!(stdout_of(source), "6(a 1 1 1)");
+    }
+
+    // Runs path with each set of arguments, with the same input, and checks
+    // they all behave the same.
+    // Backtraces are left out of the comparison if ignore_backtraces, as
+    // inlining removes calls from them.
+    fn assert_runs_agree(path: &std::path::Path, arg_sets: &[&[&str]], ignore_backtraces: bool) {
+        let stderr_of = |output: &Output| {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            match stderr.find("\nBacktrace") {
+                Some(start) if ignore_backtraces => stderr[..start + 1].to_owned(),
+                _ => stderr,
+            }
+        };
+        let outputs: Vec<Output> = arg_sets
+            .iter()
+            .map(|args| {
+                let mut child = Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+                    .args(*args)
+                    .arg(path)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                // Programs which don't read stdin may exit before it's written.
+                let _ = child.stdin.take().unwrap().write_all(b"input\n");
+                child.wait_with_output().unwrap()
+            })
+            .collect();
+        for (args, output) in arg_sets.iter().zip(&outputs).skip(1) {
+            let expected = &outputs[0];
+            assert_eq!(
+                expected.status,
+                output.status,
+                "{} {:?}",
+                path.display(),
+                args
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&expected.stdout),
+                String::from_utf8_lossy(&output.stdout),
+                "{} {:?}",
+                path.display(),
+                args
+            );
+            assert_eq!(
+                stderr_of(expected),
+                stderr_of(output),
+                "{} {:?}",
+                path.display(),
+                args
+            );
+        }
+    }
+
+    // Runs path on both the tree walker and the VM and checks they behave the
+    // same.
+    fn assert_engines_agree(path: &std::path::Path) {
+        assert_runs_agree(path, &[&[], &["--vm"]], false);
+    }
+
+    #[test]
+    fn vm_agrees_with_tree_walker_on_samples() {
+        let samples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("sample");
+        let mut count = 0;
+        for entry in std::fs::read_dir(samples).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "lisp") {
+                assert_engines_agree(&path);
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn vm_agrees_with_tree_walker() {
+        let programs = [
+            "(defun make-adder (n) (lambda (x) (+ x n)))\n\
+             (write (let add5 (make-adder 5) (add5 10)))",
+            "(defun count (n) (if (< n 1) 0 (+ 1 (count (- n 1)))))\n(write (count 20000))",
+            "(defun loop (n) (if (< n 1) \"done\" (loop (- n 1))))\n(write (loop 20000))",
+            "(write (let x 5 (eval '(+ x 1))))\n(write (let x 1 `(a ,x ,@(list x x) `(b ,(c ,x)))))",
+            "(write (list 1 2.5 #\\a \"s\" 'sym '(nested (list)) () nil true))",
+            "(defun f (x) (write x) (+ x 1))\n(write (f (f 1)))",
+            "(write \"before\")\n(write (+ 1 \"x\"))",
+            "(write (if 1 2 3))",
+            "(write ,x)",
+            // A parameter list must be a list.
+            "(write ((lambda 5 1)))",
+            "(write (let x 1 ((lambda x x))))",
+        ];
+        for program in programs {
+            assert_engines_agree(&write_script(program));
+        }
+    }
+
+    fn run_cached(path: &std::path::Path) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_lisp-interp"))
+            .arg("--cache")
+            .arg(path)
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    fn compiled_programs_are_cached_next_to_the_source() {
+        let path = write_script(
+            "(defun f (x) `(,x ,@(list 2.5 #\\a) \"s\"))\n(write (str (f 1)))\n(+ 1 \"x\")",
+        );
+        let cache = path.with_extension("rbc");
+        let first = run_cached(&path);
+        assert_eq!(String::from_utf8_lossy(&first.stdout), "(1 2.5 a s)");
+        assert!(String::from_utf8_lossy(&first.stderr).contains(":3:1."));
+
+        // A fresh cache is used rather than written again.
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        std::fs::File::options()
+            .write(true)
+            .open(&cache)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let second = run_cached(&path);
+        assert_eq!(second.stdout, first.stdout);
+        assert_eq!(second.stderr, first.stderr);
+        assert_eq!(std::fs::metadata(&cache).unwrap().modified().unwrap(), old);
+
+        // Damaged and stale caches are replaced.
+        std::fs::write(&cache, "garbage").unwrap();
+        assert_eq!(run_cached(&path).stdout, first.stdout);
+        assert_ne!(std::fs::read(&cache).unwrap(), b"garbage");
+        std::fs::write(&path, "(write \"changed\")").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&run_cached(&path).stdout),
+            "changed"
+        );
+    }
+
+    #[test]
+    fn caches_with_operands_out_of_range_are_recompiled() {
+        let path = write_script("(write \"ok\")");
+        let cache = path.with_extension("rbc");
+        assert!(run_cached(&path).status.success());
+
+        // Remove write from the names, so the instruction which looks it up
+        // refers past the end of them. The header ends with the payload's
+        // length and checksum, which are updated to match.
+        let mut bytes = std::fs::read(&cache).unwrap();
+        let start = (16..bytes.len())
+            .find(|&start| {
+                bytes[start - 16..start - 8] == ((bytes.len() - start) as u64).to_le_bytes()
+            })
+            .unwrap();
+        let names = b"\x01\0\0\0\x05\0\0\0write";
+        let at = bytes.windows(names.len()).position(|w| w == names).unwrap();
+        bytes.splice(at..at + names.len(), [0; 4]);
+        let length = (bytes.len() - start) as u64;
+        bytes[start - 16..start - 8].copy_from_slice(&length.to_le_bytes());
+        let checksum = bytes[start..]
+            .iter()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            });
+        bytes[start - 8..start].copy_from_slice(&checksum.to_le_bytes());
+        std::fs::write(&cache, &bytes).unwrap();
+
+        let output = run_cached(&path);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok");
+        assert_ne!(std::fs::read(&cache).unwrap(), bytes);
+    }
+
+    #[test]
+    fn cached_programs_behave_the_same() {
+        // The second cached run uses the cache the first wrote, if any.
+        let arg_sets: &[&[&str]] = &[&[], &["--cache"], &["--cache"]];
+        let programs = [
+            "(defun f () (g))\n(write \"ok\")",
+            "(defun f () (set! nope 1))\n(write \"ok\")",
+            "(defun f () (g))\n(defun g () 1)\n(write (str (f)))",
+            // Forms before an error still run.
+            "(write \"a\")\n(write nope)",
+            "(write \"a\")\n(",
+        ];
+        for program in programs {
+            assert_runs_agree(&write_script(program), arg_sets, false);
+        }
+    }
+
+    #[test]
+    fn optimized_programs_behave_the_same() {
+        let arg_sets: &[&[&str]] = &[&[], &["--optimize"], &["--optimize", "--vm"]];
+        let samples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("sample");
+        for entry in std::fs::read_dir(samples).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "lisp") {
+                assert_runs_agree(&path, arg_sets, true);
+            }
+        }
+        let programs = [
+            "(write (str (+ 1 2.5)))\n(write (str (< (- 3 1) 2)))\n(write (str (str nil)))",
+            "(if (< 4 5) (write \"yes\") (write \"no\"))\n(if false (write 1) (write 2))",
+            "(write (if 1 2 3))",
+            "(write (str (+ 9223372036854775807 1)))",
+            "(write (str (+ 1 \"x\")))",
+            // Arguments are evaluated once each, in order, even if unused.
+            "(defun second (a b) b)\n(write (second (write \"a\") (write \"b\")))",
+            "(defun sq (x) (+ x x))\n(defun sum-sq (a b) (+ (sq a) (sq b)))\n\
+             (write (str (sum-sq 3 (sq 2))))\n(write (str (let x 10 (sq x))))",
+            // The body's globals mustn't be captured by the caller's locals.
+            "(defun y () 1)\n(defun f (x) (+ x (y)))\n\
+             (write (str (let y (lambda () 10) (f 1))))",
+            "(defun get-x () x)\n(defun x () 1)\n(write (str (let x 5 (get-x))))",
+            // Shadowed and redefined builtins aren't folded.
+            "(write (str (let + - (+ 5 3))))",
+            "(defun str (x) \"mine\")\n(write (str 1))",
+            "(defun f (x) 1)\n(defun g () (f 0))\n(defun f (x) 2)\n(write (str (g)))",
+            // Code which looks up names at runtime keeps its names.
+            "(defun f (x) (eval 'x))\n(write (str (f 5)))",
+            "(defun f (x) `(x ,x))\n(write (str (f 5)))\n(write (str (let y 1 (eval '(+ y 1)))))",
+            "(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))\n\
+             (write (str (fib 15)))",
+            "(defun add (a b) (+ a b))\n(write (str (add 1 \"x\")))",
+            "(defun add (a b) (+ a b))\n(write (str (add 1)))",
+            "(defun twice (f x) (f (f x)))\n(defun inc (x) (+ x 1))\n\
+             (write (str (twice inc 1)))\n(write (str (twice (lambda (x) (inc x)) 5)))",
+            // Globals are resolved as they would be one form at a time.
+            "(defun f () (g))\n(write \"ok\")",
+            "(defun f () (set! nope 1))\n(write \"ok\")",
+            "(write \"a\")\n(write nope)",
+            "(write \"a\")\n(",
+        ];
+        for program in programs {
+            assert_runs_agree(&write_script(program), arg_sets, true);
+        }
+    }
+
+    #[test]
+    fn misused_builtins_are_errors_not_panics() {
+        let cases = [
+            ("(+ 1)", "Expected 2 arguments to '+'. Found 1."),
+            (
+                "(< 1 \"x\")",
+                "Expected a number for '<'. Found string \"x\".",
+            ),
+            (
+                "((lambda (x) x))",
+                "Expected 1 argument to 'lambda'. Found 0.",
+            ),
+            (
+                "(defun f (a b) a)\n(f 1 2 3)",
+                "Expected 2 arguments to 'f'. Found 3.",
+            ),
+            ("(if 1 2 3)", "Expected a bool for 'if'. Found int 1."),
+            ("(if true 1)", "Expected 3 arguments to 'if'. Found 2."),
+            ("(let 1 2 3 4)", "Expected 3 arguments to 'let'. Found 4."),
+            ("(5 1)", "Expected a function to call. Found int 5."),
+            (
+                "(str (lambda () 1))",
+                "Expected a printable value for 'str'. Found function.",
+            ),
+            ("(write)", "Expected 1 argument to 'write'. Found 0."),
+            (
+                "(defun)",
+                "Expected at least 2 arguments to 'defun'. Found 0.",
+            ),
+            ("(+ 9223372036854775807 1)", "Integer overflow in '+'"),
+            ("(error \"boom\")", "Message: boom"),
+        ];
+        for (program, expected) in cases {
+            for args in [&[][..], &["--vm"][..]] {
+                let stderr = String::from_utf8_lossy(&run_stdin_with_args(args, program).stderr)
+                    .into_owned();
+                assert!(!stderr.contains("panicked"), "{}: {}", program, stderr);
+                assert!(
+                    stderr.contains(expected),
+                    "{} {:?}: {}",
+                    program,
+                    args,
+                    stderr
+                );
+                assert!(stderr.contains("<stdin>:"), "{}: {}", program, stderr);
+            }
+        }
+    }
+
+    #[test]
+    fn runtime_errors_have_backtraces() {
+        let program = "(defun inner (x) (+ x \"a\"))\n\
+                       (defun middle (x) (+ 1 (inner x)))\n\
+                       (defun outer (x) (middle x))\n\
+                       (outer 1)";
+        let recursive = "(defun f (n) (+ 1 (f n)))\n(f 1)";
+        for args in [&[][..], &["--vm"][..]] {
+            let stderr =
+                String::from_utf8_lossy(&run_stdin_with_args(args, program).stderr).into_owned();
+            // outer's call to middle is a tail call, so outer is no longer in
+            // progress.
+            assert!(
+                stderr.ends_with(
+                    "Backtrace (innermost call first):\n    \
+                     in inner, called at <stdin>:2:24\n    \
+                     in middle, called at <stdin>:3:18\n"
+                ),
+                "{:?}: {}",
+                args,
+                stderr
+            );
+
+            let args = [args, &["--max-depth", "1000"]].concat();
+            let stderr =
+                String::from_utf8_lossy(&run_stdin_with_args(&args, recursive).stderr).into_owned();
+            assert!(
+                stderr.contains("in f, called at <stdin>:1:19"),
+                "{}",
+                stderr
+            );
+            assert!(stderr.contains(" more"), "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn errors_can_be_caught() {
+        let program = "(defun check (x) (if (< x 0) (throw x) x))\n\
+                       (write (try (check -5) (catch e (error-data e))))\n\
+                       (write (try (+ 1 \"a\") (catch e (error-kind e)) (finally (write \"!\"))))\n\
+                       (write (try (check 3) (finally (write \"?\"))))";
+        let (path, output) = run(program);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "-5!type-mismatch?3"
+        );
+        assert_engines_agree(&path);
+
+        // The cleanup runs before an uncaught error stops the program.
+        let (_, output) = run("(try (error \"boom\") (finally (write \"cleanup\")))");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "cleanup");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Message: boom"));
+    }
+
+    #[test]
+    fn macros_expand_before_evaluation() {
+        let program = "(defmacro unless (test then else) (list 'if test else then))\n\
+                       (write (unless (< 2 1) \"ran\" \"skipped\"))\n\
+                       (write (macroexpand-1 '(unless a b c)))";
+        let (path, output) = run(program);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ran(if a c b)");
+        assert_engines_agree(&path);
+
+        // A macro which expands to itself is stopped rather than looping.
+        let (_, output) = run("(defmacro forever () '(forever))\n(forever)");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("levels deep"));
+        let (_, output) = run("(defun f () (defmacro m () 1))");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("top level"));
+    }
+
+    #[test]
+    fn syntax_rules_macros_are_hygienic() {
+        let program = "(define-syntax my-or (syntax-rules ()\n\
+                         ((_ e) e)\n\
+                         ((_ e rest ...) (let tmp e (if tmp tmp (my-or rest ...))))))\n\
+                       (write (let tmp 5 (my-or false false tmp)))\n\
+                       (write (let if 1 (my-or if)))";
+        let (path, output) = run(program);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "51");
+        assert_engines_agree(&path);
+
+        // The identifiers a template introduces are renamed.
+        let expanded = stdout_of(
+            "(define-syntax swap (syntax-rules () ((_ a b) (let tmp a (list b tmp)))))\n\
+             (write (macroexpand-1 '(swap x y)))",
+        );
+        assert!(expanded.starts_with("(let;"), "{}", expanded);
+        assert!(expanded.contains(" x (list;"), "{}", expanded);
+
+        let (_, output) = run("(define-syntax m (syntax-rules () ((_ a) a)))\n(m 1 2)");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("No pattern of m matches"));
+    }
+
+    #[test]
+    fn set_mutates_bindings_closures_share() {
+        let program = "(define n 1)\n\
+                       (set! n (+ n 1))\n\
+                       (defun make-cell (value)\n\
+                         (let get (lambda () value) (lambda (new) (set! value new) (get))))\n\
+                       (define cell (make-cell 1))\n\
+                       (write (list n (cell 5)))";
+        let (path, output) = run(program);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "(2 5)");
+        assert_engines_agree(&path);
+
+        let (_, output) = run("(set! missing 1)");
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("Cannot set! \"missing\", which isn't bound."));
+        let (path, output) =
+            run("(defun f () (set! later 1))\n(write (try (f) (catch e (error-kind e))))");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "unbound-assignment"
+        );
+        assert_engines_agree(&path);
+    }
+
+    #[test]
+    fn definitions_in_bodies_are_local() {
+        let program = "(defun parity (n)\n\
+                         (defun even? (k) (if (< k 1) true (odd? (- k 1))))\n\
+                         (define odd? (lambda (k) (if (< k 1) false (even? (- k 1)))))\n\
+                         (list (even? n) (odd? n)))\n\
+                       (define odd? 0)\n\
+                       (write (list (parity 4) odd?))";
+        let (path, output) = run(program);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "((true false) 0)");
+        assert_engines_agree(&path);
+        assert_runs_agree(&path, &[&[], &["--optimize"]], false);
+    }
+
+    #[test]
+    fn reading_a_definition_before_it_runs_fails() {
+        let program = "(defun f () (define a b) (define b 2) a)
+                       (write (try (f) (catch e (list (error-kind e) (error-data e)))))";
+        let (path, output) = run(program);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "(used-before-definition b)"
+        );
+        assert_engines_agree(&path);
+        assert_runs_agree(&path, &[&[], &["--optimize"]], false);
+
+        let (path, output) = run("(defun f () (write (str b)) (define b 2) b)
+(f)");
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("Message: \"b\" used before its definition."));
+        assert_engines_agree(&path);
+    }
 }